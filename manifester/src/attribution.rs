@@ -0,0 +1,479 @@
+use failure::Error;
+use globwalk::DirEntry;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// The name of the attribution file, both the global one and those inside gallery directories.
+const ATTRIBUTION_FILE: &str = "attribution.yaml";
+/// Extension of the per-image sidecar, e.g. `IMG_1.attribution.yaml` for `IMG_1.jpg`.
+const SIDECAR_EXTENSION: &str = "attribution.yaml";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Attribution {
+    pub marked: bool,
+    pub usage_terms: String,
+    #[serde(with = "url_serde")]
+    pub web_statement: Url,
+    #[serde(with = "url_serde")]
+    pub license: Url,
+    #[serde(with = "url_serde")]
+    pub more_permissions: Url,
+    #[serde(with = "url_serde")]
+    pub attribution_url: Url,
+    pub attribution_name: String,
+    /// Only read from the global attribution file.
+    #[serde(default)]
+    pub families: Families,
+}
+
+/// Which metadata families the rights are written into. All are on unless switched off.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Families {
+    pub xmp: bool,
+    pub iptc: bool,
+    pub exif: bool,
+}
+
+impl Default for Families {
+    fn default() -> Families {
+        Families {
+            xmp: true,
+            iptc: true,
+            exif: true,
+        }
+    }
+}
+
+/// Fields an `attribution.yaml` inside the gallery, or an image's sidecar,
+/// replace for the images it covers. Anything left out is inherited.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+struct AttributionOverrides {
+    marked: Option<bool>,
+    usage_terms: Option<String>,
+    #[serde(default, with = "url_serde")]
+    web_statement: Option<Url>,
+    #[serde(default, with = "url_serde")]
+    license: Option<Url>,
+    #[serde(default, with = "url_serde")]
+    more_permissions: Option<Url>,
+    #[serde(default, with = "url_serde")]
+    attribution_url: Option<Url>,
+    attribution_name: Option<String>,
+}
+
+impl AttributionOverrides {
+    fn load(path: &Path) -> Result<AttributionOverrides, Error> {
+        let file = File::open(path)?;
+        serde_yaml::from_reader(file)
+            .map_err(|err| failure::err_msg(format!("{}: {}", path.display(), err)))
+    }
+
+    fn apply(&self, attrib: &mut Attribution) {
+        if let Some(marked) = self.marked {
+            attrib.marked = marked;
+        }
+        if let Some(usage_terms) = &self.usage_terms {
+            attrib.usage_terms = usage_terms.clone();
+        }
+        if let Some(web_statement) = &self.web_statement {
+            attrib.web_statement = web_statement.clone();
+        }
+        if let Some(license) = &self.license {
+            attrib.license = license.clone();
+        }
+        if let Some(more_permissions) = &self.more_permissions {
+            attrib.more_permissions = more_permissions.clone();
+        }
+        if let Some(attribution_url) = &self.attribution_url {
+            attrib.attribution_url = attribution_url.clone();
+        }
+        if let Some(attribution_name) = &self.attribution_name {
+            attrib.attribution_name = attribution_name.clone();
+        }
+    }
+}
+
+/// The global attribution, layered with the overrides found inside the gallery.
+/// An image takes the global values, then those of each `attribution.yaml` from the
+/// gallery root down to its own directory, then its sidecar.
+#[derive(Debug)]
+pub struct Attributions {
+    base: Attribution,
+    gallery: PathBuf,
+    directories: BTreeMap<PathBuf, AttributionOverrides>,
+}
+
+impl Attributions {
+    pub fn load(path: &Path, gallery: &Path) -> Result<Attributions, Error> {
+        let attribution_file = File::open(path)?;
+        let base: Attribution = serde_yaml::from_reader(attribution_file)?;
+
+        let mut directories = BTreeMap::new();
+        let walker = globwalk::GlobWalkerBuilder::from_patterns(gallery, &[ATTRIBUTION_FILE])
+            .follow_links(true)
+            .build()?
+            .filter_map(Result::ok);
+        for file in walker {
+            if let Some(dir) = file.path().parent() {
+                directories.insert(dir.to_path_buf(), AttributionOverrides::load(file.path())?);
+            }
+        }
+
+        Ok(Attributions {
+            base,
+            gallery: gallery.to_path_buf(),
+            directories,
+        })
+    }
+
+    /// The effective attribution of one image.
+    pub fn for_image(&self, image: &Path) -> Result<Attribution, Error> {
+        let mut attrib = self.base.clone();
+        if let Some(parent) = image.parent() {
+            let mut dirs = parent
+                .ancestors()
+                .take_while(|dir| dir.starts_with(&self.gallery))
+                .collect::<Vec<&Path>>();
+            dirs.reverse();
+            for dir in dirs {
+                if let Some(overrides) = self.directories.get(dir) {
+                    overrides.apply(&mut attrib);
+                }
+            }
+        }
+
+        let sidecar = image.with_extension(SIDECAR_EXTENSION);
+        if sidecar.exists() {
+            AttributionOverrides::load(&sidecar)?.apply(&mut attrib);
+        }
+        Ok(attrib)
+    }
+}
+
+/// Writes rights into every image whose attribution, after its overrides, is `Marked`.
+/// Images that resolve to `Marked: false` are left alone, whatever they carry already.
+pub fn write_attribution(images: &[DirEntry], attributions: &Attributions) -> Result<(), Error> {
+    println!("Adding attribution.");
+    if attributions.base.families.xmp {
+        register_cc_namespace()?;
+    }
+
+    let bar = crate::progress_bar(images.len());
+    let updated = images
+        .par_iter()
+        .map(|file| {
+            let marked = attributions.for_image(file.path()).and_then(|attrib| {
+                if attrib.marked {
+                    mark_rights(file.path(), &attrib).map(Some)
+                } else {
+                    Ok(None)
+                }
+            });
+            bar.inc(1);
+            marked
+        })
+        .collect::<Result<Vec<Option<bool>>, Error>>()?;
+    bar.finish();
+    let count = updated
+        .iter()
+        .filter(|&&changed| changed == Some(true))
+        .count();
+    let unmarked = updated.iter().filter(|changed| changed.is_none()).count();
+    println!(
+        "Updated rights on {} images, {} were already correct, {} are not marked.",
+        count,
+        updated.len() - count - unmarked,
+        unmarked
+    );
+    Ok(())
+}
+
+/// Makes the Creative Commons tags known to exiv2, unless they already are.
+/// Other namespaces, such as those Lightroom registers, are left registered so their tags survive.
+fn register_cc_namespace() -> Result<(), Error> {
+    if !rexiv2::is_xmp_tag("Xmp.cc.license") {
+        rexiv2::register_xmp_namespace("http://creativecommons.org/ns#/", "cc")?;
+    }
+    Ok(())
+}
+
+/// How a rights tag on an image differs from its attribution.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Finding {
+    Missing,
+    Different,
+    /// The licence itself differs, rather than just how it's described.
+    OtherLicense,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagReport {
+    pub tag: &'static str,
+    pub finding: Finding,
+    pub expected: String,
+    pub found: Option<String>,
+}
+
+/// The rights problems of one image, relative to the gallery root.
+#[derive(Debug, Serialize)]
+pub struct FileReport {
+    pub file: String,
+    /// Set if the image's metadata or attribution couldn't be read at all.
+    pub error: Option<String>,
+    pub tags: Vec<TagReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct AuditReport<'a> {
+    checked: usize,
+    /// Images whose attribution resolves to `Marked: false`, which are left unchecked.
+    unmarked: usize,
+    files: &'a [FileReport],
+}
+
+/// Reads the rights tags back from every marked image and reports those that are missing
+/// or disagree with the attribution the image should have. Fails if any do.
+/// Images that resolve to `Marked: false` are counted but not checked, as
+/// `write_attribution` leaves them alone.
+pub fn audit_rights(
+    images: &[DirEntry],
+    gallery: &Path,
+    attributions: &Attributions,
+    json: bool,
+) -> Result<(), Error> {
+    register_cc_namespace()?;
+    let audited = images
+        .par_iter()
+        .map(|file| {
+            let name = file
+                .path()
+                .strip_prefix(gallery)
+                .unwrap_or_else(|_| file.path())
+                .to_string_lossy()
+                .into_owned();
+            match audit_image(file.path(), attributions) {
+                Ok(None) => None,
+                Ok(Some(tags)) => Some(FileReport {
+                    file: name,
+                    error: None,
+                    tags,
+                }),
+                Err(err) => Some(FileReport {
+                    file: name,
+                    error: Some(err.to_string()),
+                    tags: Vec::new(),
+                }),
+            }
+        })
+        .collect::<Vec<Option<FileReport>>>();
+    let unmarked = audited.iter().filter(|report| report.is_none()).count();
+    let mut reports = audited
+        .into_iter()
+        .flatten()
+        .filter(|report| report.error.is_some() || !report.tags.is_empty())
+        .collect::<Vec<FileReport>>();
+    reports.sort_by(|a, b| a.file.cmp(&b.file));
+
+    if json {
+        let report = AuditReport {
+            checked: images.len() - unmarked,
+            unmarked,
+            files: &reports,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for report in &reports {
+            println!("{}:", report.file);
+            if let Some(err) = &report.error {
+                println!("  unreadable: {}", err);
+            }
+            for tag in &report.tags {
+                match (&tag.finding, &tag.found) {
+                    (Finding::Missing, _) | (_, None) => {
+                        println!("  missing {}, expected {:?}", tag.tag, tag.expected)
+                    }
+                    (Finding::OtherLicense, Some(found)) => println!(
+                        "  licensed under {:?} in {}, expected {:?}",
+                        found, tag.tag, tag.expected
+                    ),
+                    (Finding::Different, Some(found)) => {
+                        println!("  {} is {:?}, expected {:?}", tag.tag, found, tag.expected)
+                    }
+                }
+            }
+        }
+    }
+
+    if reports.is_empty() {
+        if !json {
+            println!(
+                "Rights on all {} marked images are correct, {} are not marked.",
+                images.len() - unmarked,
+                unmarked
+            );
+        }
+        return Ok(());
+    }
+    Err(failure::err_msg(format!(
+        "{} of {} marked images have missing or incorrect rights",
+        reports.len(),
+        images.len() - unmarked
+    )))
+}
+
+/// The tags that are wrong on a marked image, or nothing if the image isn't marked.
+fn audit_image(path: &Path, attributions: &Attributions) -> Result<Option<Vec<TagReport>>, Error> {
+    let attrib = attributions.for_image(path)?;
+    if !attrib.marked {
+        return Ok(None);
+    }
+    let meta = rexiv2::Metadata::new_from_path(path)?;
+    Ok(Some(
+        rights_tags(&attrib, &meta)
+            .into_iter()
+            .filter_map(|(tag, expected)| {
+                let found = tag_text(&meta, tag);
+                let finding = match &found {
+                    None => Finding::Missing,
+                    Some(value) if *value == expected => return None,
+                    Some(_) if tag == "Xmp.cc.license" => Finding::OtherLicense,
+                    Some(_) => Finding::Different,
+                };
+                Some(TagReport {
+                    tag,
+                    finding,
+                    expected,
+                    found,
+                })
+            })
+            .collect(),
+    ))
+}
+
+/// The rights tags every image should carry in each enabled family, with their values.
+/// The same fields feed each family: the usage terms are the copyright notice,
+/// and the attribution name is the author.
+fn rights_tags(attrib: &Attribution, meta: &rexiv2::Metadata) -> Vec<(&'static str, String)> {
+    let mut tags = Vec::new();
+    if attrib.families.xmp && meta.supports_xmp() {
+        let marked = match attrib.marked {
+            true => "True",
+            false => "False",
+        };
+        tags.extend(vec![
+            ("Xmp.xmpRights.Marked", marked.to_string()),
+            ("Xmp.xmpRights.UsageTerms", attrib.usage_terms.clone()),
+            ("Xmp.dc.rights", attrib.usage_terms.clone()),
+            (
+                "Xmp.xmpRights.WebStatement",
+                attrib.web_statement.as_str().to_string(),
+            ),
+            ("Xmp.cc.license", attrib.license.as_str().to_string()),
+            (
+                "Xmp.cc.morePermissions",
+                attrib.more_permissions.as_str().to_string(),
+            ),
+            (
+                "Xmp.cc.attributionURL",
+                attrib.attribution_url.as_str().to_string(),
+            ),
+            ("Xmp.cc.attributionName", attrib.attribution_name.clone()),
+        ]);
+    }
+    if attrib.families.iptc && meta.supports_iptc() {
+        tags.extend(vec![
+            ("Iptc.Application2.Copyright", attrib.usage_terms.clone()),
+            ("Iptc.Application2.Byline", attrib.attribution_name.clone()),
+            ("Iptc.Application2.Credit", attrib.attribution_name.clone()),
+        ]);
+    }
+    if attrib.families.exif && meta.supports_exif() {
+        tags.extend(vec![
+            ("Exif.Image.Copyright", attrib.usage_terms.clone()),
+            ("Exif.Image.Artist", attrib.attribution_name.clone()),
+        ]);
+    }
+    tags
+}
+
+/// Merges the rights tags into the image's existing metadata, leaving everything else alone.
+/// Returns whether the file was written; images that already carry the right values
+/// are not touched, so their modification times stay put.
+fn mark_rights(path: &Path, attrib: &Attribution) -> Result<bool, Error> {
+    let meta = rexiv2::Metadata::new_from_path(path)?;
+    let stale = rights_tags(attrib, &meta)
+        .into_iter()
+        .filter(|(tag, value)| tag_text(&meta, tag).as_ref() != Some(value))
+        .collect::<Vec<(&str, String)>>();
+    if stale.is_empty() {
+        return Ok(false);
+    }
+
+    for (tag, value) in &stale {
+        meta.set_tag_string(tag, value)?;
+    }
+    meta.save_to_file(path)?;
+    Ok(true)
+}
+
+/// Reads a tag as text, dropping the `lang="x-default"` qualifier
+/// exiv2 puts in front of XMP language alternatives such as `dc:rights`.
+fn tag_text(meta: &rexiv2::Metadata, tag: &str) -> Option<String> {
+    let value = meta.get_tag_string(tag).ok()?;
+    if value.starts_with("lang=") {
+        value.split_once(' ').map(|(_, text)| text.to_string())
+    } else {
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn unmarked_directories_pass_the_audit() {
+        let root = std::env::temp_dir().join(format!("manifester-audit-{}", std::process::id()));
+        let gallery = root.join("gallery");
+        fs::create_dir_all(gallery.join("trip")).unwrap();
+        fs::write(
+            root.join(ATTRIBUTION_FILE),
+            "Marked: true\n\
+             UsageTerms: All rights reserved\n\
+             WebStatement: https://example.com/cc/\n\
+             License: http://creativecommons.org/licenses/by-nc-sa/4.0/\n\
+             MorePermissions: https://example.com/license/\n\
+             AttributionUrl: https://example.com/\n\
+             AttributionName: John Smith\n",
+        )
+        .unwrap();
+        fs::write(
+            gallery.join("trip").join(ATTRIBUTION_FILE),
+            "Marked: false\n",
+        )
+        .unwrap();
+        fs::write(gallery.join("trip").join("photo.jpg"), "not an image").unwrap();
+
+        let attributions = Attributions::load(&root.join(ATTRIBUTION_FILE), &gallery).unwrap();
+        let images = globwalk::GlobWalkerBuilder::from_patterns(&gallery, &["*.jpg"])
+            .build()
+            .unwrap()
+            .filter_map(Result::ok)
+            .collect::<Vec<DirEntry>>();
+        let audited = audit_image(images[0].path(), &attributions).map(|tags| tags.is_none());
+        let result = audit_rights(&images, &gallery, &attributions, true);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(images.len(), 1);
+        assert!(audited.unwrap());
+        assert!(result.is_ok());
+    }
+}
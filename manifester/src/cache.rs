@@ -0,0 +1,189 @@
+use crate::thumbs::{swaps_axes, Settings};
+use chrono::NaiveDateTime;
+use failure::Error;
+use image::GenericImageView;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Remembers what each gallery image looked like when its derivatives were last made,
+/// so unchanged sources can be skipped without being decoded.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BuildCache {
+    /// Keyed by the source path, relative to the gallery root.
+    pub sources: BTreeMap<String, CacheEntry>,
+    /// What we've read from each image's header, keyed like `sources`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, ImageHeader>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// Modification time in nanoseconds since the epoch.
+    pub modified: u64,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the file contents.
+    pub hash: String,
+    /// The settings the derivatives were made with.
+    pub settings: Settings,
+    /// Whether the derivatives were made with EXIF orientation applied.
+    /// Older caches don't record this, so it defaults to false.
+    #[serde(default)]
+    pub oriented: bool,
+    /// Average colours of horizontal bands of the image, top to bottom, as `#rrggbb`.
+    #[serde(default)]
+    pub placeholder: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageHeader {
+    pub modified: u64,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Capture time from the EXIF data, formatted as `YYYY-MM-DDTHH:MM:SS` local time.
+    pub captured: Option<String>,
+    /// Whether `width` and `height` are as displayed, after EXIF orientation.
+    /// Older caches don't record this, so it defaults to false.
+    #[serde(default)]
+    pub oriented: bool,
+}
+
+impl ImageHeader {
+    pub fn ratio(&self) -> f64 {
+        self.width as f64 / self.height as f64
+    }
+}
+
+impl BuildCache {
+    pub fn load(path: &Path) -> Result<BuildCache, Error> {
+        load(path)
+    }
+
+    /// Reads an image's size and capture time, from the cache if the file is unchanged,
+    /// otherwise from its header. Only decodes the whole image if exiv2 can't tell us the size.
+    pub fn header(&self, key: &str, path: &Path) -> Result<ImageHeader, Error> {
+        let (modified, size) = stat(path)?;
+        if let Some(known) = self.headers.get(key) {
+            if known.modified == modified && known.size == size && known.oriented {
+                return Ok(known.clone());
+            }
+        }
+
+        let meta = rexiv2::Metadata::new_from_path(path)?;
+        let (width, height) = match (meta.get_pixel_width(), meta.get_pixel_height()) {
+            (width, height) if width > 0 && height > 0 => (width as u32, height as u32),
+            _ => image::open(path)?.dimensions(),
+        };
+        // The stored pixels of portrait phone photos are often landscape.
+        let (width, height) = if swaps_axes(meta.get_orientation()) {
+            (height, width)
+        } else {
+            (width, height)
+        };
+        // Cameras without a set clock write zeros here, which won't parse and are ignored.
+        let captured = meta
+            .get_tag_string("Exif.Photo.DateTimeOriginal")
+            .ok()
+            .and_then(|date| NaiveDateTime::parse_from_str(date.trim(), "%Y:%m:%d %H:%M:%S").ok())
+            .map(|date| date.format("%Y-%m-%dT%H:%M:%S").to_string());
+        Ok(ImageHeader {
+            modified,
+            size,
+            width,
+            height,
+            captured,
+            oriented: true,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        save(self, path)
+    }
+}
+
+/// Reads a JSON cache, starting afresh if it doesn't exist yet.
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T, Error> {
+    match File::open(path) {
+        Ok(buffer) => Ok(serde_json::from_reader(BufReader::new(buffer))?),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Writes a JSON cache to a hidden partial file first and renames it into place,
+/// so an interrupted run never leaves a truncated cache that can't be loaded.
+pub fn save<T: Serialize>(value: &T, path: &Path) -> Result<(), Error> {
+    let name = path
+        .file_name()
+        .and_then(|p| p.to_str())
+        .ok_or_else(|| failure::err_msg("File name unwrap issue."))?;
+    let partial = path.with_file_name(format!(".{}.partial", name));
+
+    let written = File::create(&partial)
+        .map_err(Error::from)
+        .and_then(|buffer| Ok(serde_json::to_writer_pretty(BufWriter::new(buffer), value)?))
+        .and_then(|_| Ok(fs::rename(&partial, path)?));
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    written
+}
+
+/// The cheap-to-read state of a source file: its modification time and size.
+pub fn stat(path: &Path) -> Result<(u64, u64), Error> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok((modified.as_nanos() as u64, metadata.len()))
+}
+
+pub fn hash(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_replace_the_cache_whole() {
+        let dir = std::env::temp_dir().join(format!("manifester-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.json");
+        fs::write(&path, "{\"sources\": {").unwrap();
+
+        let mut cache = BuildCache::default();
+        cache.headers.insert(
+            "trip/photo.jpg".to_string(),
+            ImageHeader {
+                modified: 1,
+                size: 2,
+                width: 3,
+                height: 4,
+                captured: None,
+                oriented: true,
+            },
+        );
+        cache.save(&path).unwrap();
+        let loaded = BuildCache::load(&path).unwrap();
+        let leftovers: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.headers, cache.headers);
+        assert_eq!(leftovers.len(), 1);
+    }
+
+    #[test]
+    fn missing_caches_start_afresh() {
+        let cache: BuildCache = load(Path::new("/nonexistent/cache.json")).unwrap();
+        assert!(cache.sources.is_empty() && cache.headers.is_empty());
+    }
+}
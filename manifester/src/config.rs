@@ -0,0 +1,534 @@
+use crate::countries;
+use crate::geocode::GeocoderSettings;
+use crate::local::LocalName;
+use crate::thumbs::Settings;
+use failure::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::File;
+use std::iter::FromIterator;
+use std::path::Path;
+
+/// The key used inside a country's place list to hold the country's local name.
+const LOCAL_KEY: &str = "Local";
+/// The key that gives a country's ISO alpha-3 code, when its name doesn't find it.
+const CODE_KEY: &str = "Code";
+/// The key that marks a country as a city-state, which is also its only location.
+const CITY_STATE_KEY: &str = "CityState";
+/// Appended to a city-state's key for its location's identifier unless `Identifier` is set,
+/// as countries and locations can't share a constructor name in Manifest.elm.
+const CITY_STATE_SUFFIX: &str = "City";
+
+/// A country identifier, as listed under `places` in odyssey.yaml.
+/// These become constructors of the `Country` type in Manifest.elm.
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Country(String);
+
+impl fmt::Display for Country {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Country {
+    pub fn name(&self) -> String {
+        split_camel_case(&self.0)
+    }
+}
+
+/// A location, as listed under a country in odyssey.yaml.
+/// These become constructors of the `Location` type in Manifest.elm.
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    /// The Elm constructor: the key, followed by the country if other countries use the key too.
+    identifier: String,
+    key: String,
+    country: Country,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.identifier)
+    }
+}
+
+impl Location {
+    /// The location's key under its country in odyssey.yaml.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn country(&self) -> &Country {
+        &self.country
+    }
+}
+
+#[derive(Debug)]
+pub struct CountryDetails {
+    /// ISO alpha-3 code to use instead of looking the name up in cca3.json.
+    pub code: Option<String>,
+    pub local: Option<LocalName>,
+    pub locations: BTreeMap<Location, LocationDetails>,
+}
+
+#[derive(Debug)]
+pub struct LocationDetails {
+    /// English display name. Derived from the identifier unless overridden.
+    pub name: String,
+    pub local: Option<LocalName>,
+    /// Coordinates to use instead of geocoding.
+    pub position: Option<Position>,
+    /// What to ask the geocoder for, if not the name and country.
+    pub query: Option<String>,
+    /// The key the location had before, so its place in cities.json is kept.
+    pub renamed_from: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// A location's value in odyssey.yaml: either just its local name,
+/// or a mapping that can also override the display name and how it is geocoded.
+/// `CityState` may also be a flag.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LocationEntry {
+    Local(String),
+    Flag(bool),
+    Detailed(LocationOverrides),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+struct LocationOverrides {
+    name: Option<String>,
+    /// The constructor in Manifest.elm, if not the key.
+    identifier: Option<String>,
+    local: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    query: Option<String>,
+    renamed_from: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    places: BTreeMap<Country, BTreeMap<String, Option<LocationEntry>>>,
+    trips: Vec<TripEntry>,
+    #[serde(default)]
+    thumbnails: Settings,
+    #[serde(default)]
+    geocoder: GeocoderSettings,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub places: BTreeMap<Country, CountryDetails>,
+    pub trips: Vec<Trip>,
+    pub thumbnails: Settings,
+    pub geocoder: GeocoderSettings,
+}
+
+/// A trip as written in odyssey.yaml. Cities are location keys,
+/// or `Country/Location` where the key is used in several countries.
+#[derive(Debug, Deserialize)]
+struct TripEntry {
+    name: String,
+    description: String,
+    cities: Vec<String>,
+    dates: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Trip {
+    pub name: String,
+    pub description: String,
+    pub cities: Vec<Location>,
+    pub dates: Vec<String>,
+}
+
+impl Trip {
+    pub fn id_string(&self) -> String {
+        let mut id = self.description.to_string();
+        id.retain(|c| c != ' ' && c != '/');
+        id
+    }
+}
+
+impl Config {
+    /// Reads odyssey.yaml and checks that every place and trip is usable
+    /// before anything is geocoded or written.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let config_file = File::open(path)?;
+        Config::parse(serde_yaml::from_reader(config_file)?)
+    }
+
+    /// Reads a configuration from its YAML text.
+    #[cfg(test)]
+    pub fn from_yaml(text: &str) -> Result<Config, Error> {
+        Config::parse(serde_yaml::from_str(text)?)
+    }
+
+    fn parse(raw: ConfigFile) -> Result<Config, Error> {
+        let mut problems: Vec<String> = Vec::new();
+        let mut warnings: Vec<String> = Vec::new();
+        // Keys used in more than one country are told apart by their country in Manifest.elm.
+        let mut seen: BTreeSet<&String> = BTreeSet::new();
+        let shared: BTreeSet<String> = raw
+            .places
+            .values()
+            .flat_map(|entries| entries.keys())
+            .filter(|key| {
+                ![LOCAL_KEY, CODE_KEY, CITY_STATE_KEY].contains(&key.as_str()) && !seen.insert(key)
+            })
+            .cloned()
+            .collect();
+
+        let mut places = BTreeMap::new();
+        for (country, entries) in raw.places {
+            if !is_identifier(&country.0) {
+                problems.push(format!(
+                    "Country {} must be written in CamelCase without spaces",
+                    country
+                ));
+            }
+            let mut details = CountryDetails {
+                code: None,
+                local: None,
+                locations: BTreeMap::new(),
+            };
+            let mut city_state = None;
+            let mut listed: Vec<(String, LocationOverrides, bool)> = Vec::new();
+            for (key, entry) in entries {
+                if key == LOCAL_KEY {
+                    match entry {
+                        Some(LocationEntry::Local(local)) => {
+                            details.local = local_name(
+                                &local,
+                                &country.to_string(),
+                                &mut problems,
+                                &mut warnings,
+                            )
+                        }
+                        None => {}
+                        Some(_) => problems.push(format!(
+                            "{} of {} must be a plain string",
+                            LOCAL_KEY, country
+                        )),
+                    }
+                    continue;
+                }
+                if key == CODE_KEY {
+                    match entry {
+                        Some(LocationEntry::Local(code)) if countries::is_code(&code) => {
+                            details.code = Some(code)
+                        }
+                        _ => problems.push(format!(
+                            "{} of {} must be three capital letters, like GBR",
+                            CODE_KEY, country
+                        )),
+                    }
+                    continue;
+                }
+                if key == CITY_STATE_KEY {
+                    match entry {
+                        Some(LocationEntry::Flag(true)) => {
+                            city_state = Some(LocationOverrides::default())
+                        }
+                        Some(LocationEntry::Flag(false)) | None => {}
+                        Some(LocationEntry::Detailed(overrides)) => city_state = Some(overrides),
+                        Some(LocationEntry::Local(_)) => problems.push(format!(
+                            "{} of {} must be true, or a mapping like a location's",
+                            CITY_STATE_KEY, country
+                        )),
+                    }
+                    continue;
+                }
+                if !is_identifier(&key) {
+                    problems.push(format!(
+                        "Location {} in {} must be written in CamelCase without spaces",
+                        key, country
+                    ));
+                }
+                let overrides = match entry {
+                    None => LocationOverrides::default(),
+                    Some(LocationEntry::Local(local)) => LocationOverrides {
+                        local: Some(local),
+                        ..LocationOverrides::default()
+                    },
+                    Some(LocationEntry::Detailed(overrides)) => overrides,
+                    Some(LocationEntry::Flag(_)) => {
+                        problems.push(format!(
+                            "Location {} in {} must be a local name or a mapping",
+                            key, country
+                        ));
+                        LocationOverrides::default()
+                    }
+                };
+                listed.push((key, overrides, false));
+            }
+
+            // A city-state is listed as a location sharing its key, name and local name.
+            if let Some(mut overrides) = city_state {
+                if listed.iter().any(|(key, _, _)| *key == country.0) {
+                    problems.push(format!(
+                        "{} is a city-state, so can't also list a location {}",
+                        country, country
+                    ));
+                }
+                overrides.name = overrides.name.or_else(|| Some(country.name()));
+                overrides.identifier = overrides
+                    .identifier
+                    .or_else(|| Some(format!("{}{}", country, CITY_STATE_SUFFIX)));
+                listed.push((country.0.clone(), overrides, true));
+            }
+
+            for (key, overrides, is_city_state) in listed {
+                let position = match (overrides.lat, overrides.lon) {
+                    (None, None) => None,
+                    (Some(lat), Some(lon)) if lat.abs() <= 90.0 && lon.abs() <= 180.0 => {
+                        Some(Position { lat, lon })
+                    }
+                    (Some(_), Some(_)) => {
+                        problems.push(format!(
+                            "Coordinates of {} in {} are out of range",
+                            key, country
+                        ));
+                        None
+                    }
+                    _ => {
+                        problems.push(format!(
+                            "Location {} in {} needs both Lat and Lon",
+                            key, country
+                        ));
+                        None
+                    }
+                };
+                let identifier = match overrides.identifier {
+                    Some(identifier) => {
+                        if !is_identifier(&identifier) {
+                            problems.push(format!(
+                                "Identifier {} of {} in {} must be written in CamelCase without spaces",
+                                identifier, key, country
+                            ));
+                        }
+                        identifier
+                    }
+                    None if shared.contains(&key) => format!("{}{}", key, country),
+                    None => key.clone(),
+                };
+                details.locations.insert(
+                    Location {
+                        identifier,
+                        key: key.clone(),
+                        country: country.clone(),
+                    },
+                    LocationDetails {
+                        name: overrides.name.unwrap_or_else(|| split_camel_case(&key)),
+                        local: match overrides.local {
+                            Some(local) => local_name(
+                                &local,
+                                &format!("{} in {}", key, country),
+                                &mut problems,
+                                &mut warnings,
+                            ),
+                            None if is_city_state => details.local.clone(),
+                            None => None,
+                        },
+                        position,
+                        query: overrides.query,
+                        renamed_from: overrides.renamed_from,
+                    },
+                );
+            }
+            places.insert(country, details);
+        }
+
+        // Countries and locations share a namespace once they become Elm constructors.
+        let mut identifiers: BTreeSet<String> =
+            BTreeSet::from_iter(places.keys().map(|c| c.0.clone()));
+        for (country, details) in &places {
+            for location in details.locations.keys() {
+                if !identifiers.insert(location.identifier.clone()) {
+                    problems.push(format!(
+                        "Location {} in {} clashes with another country or location",
+                        location, country
+                    ));
+                }
+            }
+        }
+
+        for (country, details) in &places {
+            let mut renames: BTreeSet<&String> = BTreeSet::new();
+            for (location, place) in &details.locations {
+                if let Some(old) = &place.renamed_from {
+                    if !is_identifier(old) || details.locations.keys().any(|l| l.key == *old) {
+                        problems.push(format!(
+                            "{} in {} must be renamed from a key that is no longer used, not {}",
+                            location.key, country, old
+                        ));
+                    } else if !renames.insert(old) {
+                        problems.push(format!(
+                            "{} in {} is renamed from {}, which another location is renamed from too",
+                            location.key, country, old
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut trips = Vec::new();
+        for trip in raw.trips {
+            let mut cities = Vec::new();
+            for city in &trip.cities {
+                let (country, key) = match city.find('/') {
+                    Some(slash) => (Some(&city[..slash]), &city[slash + 1..]),
+                    None => (None, city.as_str()),
+                };
+                let matches = places
+                    .values()
+                    .flat_map(|details| details.locations.keys())
+                    .filter(|location| {
+                        location.key == key && country.map_or(true, |c| location.country.0 == c)
+                    })
+                    .collect::<Vec<&Location>>();
+                match matches.as_slice() {
+                    [location] => cities.push((*location).clone()),
+                    [] => problems.push(format!(
+                        "Trip {} visits {}, which is not listed in places",
+                        trip.name, city
+                    )),
+                    _ => problems.push(format!(
+                        "Trip {} visits {}, which is in several countries; write it as {}",
+                        trip.name,
+                        city,
+                        matches
+                            .iter()
+                            .map(|location| format!("{}/{}", location.country, key))
+                            .collect::<Vec<String>>()
+                            .join(" or ")
+                    )),
+                }
+            }
+            trips.push(Trip {
+                name: trip.name,
+                description: trip.description,
+                cities,
+                dates: trip.dates,
+            });
+        }
+
+        let thumbnails = &raw.thumbnails;
+        if thumbnails.sizes.is_empty() || thumbnails.sizes.contains(&0) {
+            problems.push("Thumbnail sizes must be a non-empty list of positive sizes".to_string());
+        }
+        if !(thumbnails.panorama_ratio > 0.0 && thumbnails.panorama_stretch >= 1.0) {
+            problems.push(
+                "Thumbnail panorama_ratio must be positive and panorama_stretch at least 1"
+                    .to_string(),
+            );
+        }
+
+        for warning in &warnings {
+            println!("Warning: {}", warning);
+        }
+        if !problems.is_empty() {
+            return Err(failure::err_msg(format!(
+                "Invalid configuration:\n  {}",
+                problems.join("\n  ")
+            )));
+        }
+
+        Ok(Config {
+            places,
+            trips,
+            thumbnails: raw.thumbnails,
+            geocoder: raw.geocoder,
+        })
+    }
+
+    /// The ISO alpha-3 code of a country: its `Code` if given, otherwise found by name.
+    pub fn code(
+        &self,
+        country: &Country,
+        cca3: &BTreeMap<String, String>,
+    ) -> Result<String, Error> {
+        match self
+            .places
+            .get(country)
+            .and_then(|details| details.code.clone())
+        {
+            Some(code) => Ok(code),
+            None => countries::code(&country.name(), cca3).map_err(failure::err_msg),
+        }
+    }
+
+    /// Finds a configured location from its gallery directories, which are named
+    /// after the country and location with underscores for spaces.
+    pub fn gallery_location(&self, country: &str, location: &str) -> Result<&Location, Error> {
+        let (country, details) = self
+            .places
+            .iter()
+            .find(|(c, _)| c.name().replace(' ', "_") == country)
+            .ok_or_else(|| {
+                failure::err_msg(format!("{} is not a country in odyssey.yaml", country))
+            })?;
+        details
+            .locations
+            .iter()
+            .find(|(_, place)| place.name.replace(' ', "_") == location)
+            .map(|(location, _)| location)
+            .ok_or_else(|| {
+                failure::err_msg(format!(
+                    "{} is not a location in {} in odyssey.yaml",
+                    location, country
+                ))
+            })
+    }
+}
+
+/// Checks and normalises a local name, noting anything wrong with it against the place it names.
+fn local_name(
+    raw: &str,
+    place: &str,
+    problems: &mut Vec<String>,
+    warnings: &mut Vec<String>,
+) -> Option<LocalName> {
+    let mut found = Vec::new();
+    let parsed = LocalName::parse(raw, &mut found);
+    warnings.extend(
+        found
+            .into_iter()
+            .map(|warning| format!("Local name {:?} of {} {}", raw, place, warning)),
+    );
+    match parsed {
+        Ok(name) => Some(name),
+        Err(problem) => {
+            problems.push(format!("Local name {:?} of {} {}", raw, place, problem));
+            None
+        }
+    }
+}
+
+/// Elm constructors must start with an uppercase letter, and we use them as path components.
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_uppercase() => chars.all(|c| c.is_ascii_alphanumeric()),
+        _ => false,
+    }
+}
+
+fn split_camel_case(identifier: &str) -> String {
+    let mut name: Vec<char> = Vec::new();
+    for (idx, c) in identifier.char_indices() {
+        if idx > 0 && c.is_uppercase() {
+            name.push(' ');
+        }
+        name.push(c);
+    }
+    String::from_iter(name)
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use strsim::jaro_winkler;
+
+/// Common English names of countries that cca3.json spells the ISO way, so `SouthKorea`
+/// works although no key could spell its "Korea (Republic of)".
+const ALIASES: &[(&str, &str)] = &[
+    ("Bolivia", "BOL"),
+    ("Brunei", "BRN"),
+    ("Cape Verde", "CPV"),
+    ("Czechia", "CZE"),
+    ("Democratic Republic of the Congo", "COD"),
+    ("East Timor", "TLS"),
+    ("Faroe Islands", "FRO"),
+    ("Great Britain", "GBR"),
+    ("Iran", "IRN"),
+    ("Ivory Coast", "CIV"),
+    ("Laos", "LAO"),
+    ("Macau", "MAC"),
+    ("Macedonia", "MKD"),
+    ("Micronesia", "FSM"),
+    ("Moldova", "MDA"),
+    ("North Korea", "PRK"),
+    ("North Macedonia", "MKD"),
+    ("Palestine", "PSE"),
+    ("South Korea", "KOR"),
+    ("Syria", "SYR"),
+    ("Taiwan", "TWN"),
+    ("Tanzania", "TZA"),
+    ("The Netherlands", "NLD"),
+    ("United States", "USA"),
+    ("Vatican City", "VAT"),
+    ("Venezuela", "VEN"),
+    ("Viet Nam", "VNM"),
+];
+
+/// How alike two names must be, by Jaro-Winkler similarity, to be suggested.
+const SUGGESTION_THRESHOLD: f64 = 0.85;
+
+/// Finds the ISO alpha-3 code of a country by its name in cca3.json, or by a common alias.
+/// If neither knows it, the error suggests the closest names.
+pub fn code(name: &str, cca3: &BTreeMap<String, String>) -> Result<String, String> {
+    if let Some(code) = cca3.get(name) {
+        return Ok(code.clone());
+    }
+    if let Some((_, code)) = ALIASES.iter().find(|(alias, _)| *alias == name) {
+        return Ok(code.to_string());
+    }
+
+    let lowercase = name.to_lowercase();
+    let mut candidates: Vec<(f64, String)> = cca3
+        .iter()
+        .map(|(known, code)| (known.as_str(), code.as_str()))
+        .chain(ALIASES.iter().cloned())
+        .map(|(known, code)| {
+            (
+                jaro_winkler(&lowercase, &known.to_lowercase()),
+                format!("{} ({})", known, code),
+            )
+        })
+        .filter(|(similarity, _)| *similarity >= SUGGESTION_THRESHOLD)
+        .collect();
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    candidates.dedup_by(|a, b| a.1 == b.1);
+
+    let suggestions = candidates
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate)
+        .collect::<Vec<String>>();
+    Err(if suggestions.is_empty() {
+        format!("{} does not exist in cca3.json", name)
+    } else {
+        format!(
+            "{} does not exist in cca3.json, did you mean {}?",
+            name,
+            suggestions.join(", ")
+        )
+    })
+}
+
+/// An explicit code is an ISO alpha-3 code, or one of the user assigned ones
+/// Natural Earth uses, such as KOS.
+pub fn is_code(code: &str) -> bool {
+    code.len() == 3 && code.chars().all(|c| c.is_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cca3() -> BTreeMap<String, String> {
+        let file: serde_json::Value =
+            serde_json::from_str(include_str!("../world/cca3.json")).unwrap();
+        file["codes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|code| {
+                (
+                    code["name"].as_str().unwrap().to_string(),
+                    code["alpha-3"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn aliases_are_only_names_cca3_spells_otherwise() {
+        let cca3 = cca3();
+        for (alias, code) in ALIASES {
+            assert!(!cca3.contains_key(*alias), "{} is in cca3.json", alias);
+            assert!(
+                cca3.values().any(|known| known == code),
+                "{} of {} is not in cca3.json",
+                code,
+                alias
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_names_suggest_the_closest() {
+        let cca3 = cca3();
+        assert_eq!(code("South Korea", &cca3), Ok("KOR".to_string()));
+        assert_eq!(
+            code("Austrija", &cca3),
+            Err("Austrija does not exist in cca3.json, did you mean Austria (AUT), Australia (AUS)?".to_string())
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use crate::config::{Config, Country, Location};
+use crate::geocode::{self, CountryShape, GeocodeCache, Search};
+use crate::local::LocalName;
+use failure::Error;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// A native-language name the geocoder knows for a place that has none in odyssey.yaml.
+struct Proposal {
+    country: Country,
+    location: Option<Location>,
+    local: String,
+}
+
+/// Where a place is written in odyssey.yaml.
+struct Entry {
+    line: usize,
+    /// How far the entry's own keys are indented, if it is a mapping
+    /// such as a country, or a location with a `Name:` override.
+    children: Option<usize>,
+}
+
+/// A change to a single line of odyssey.yaml.
+enum Edit {
+    Replace(usize, String),
+    InsertAfter(usize, String),
+}
+
+impl Edit {
+    fn line(&self) -> usize {
+        match self {
+            Edit::Replace(line, _) | Edit::InsertAfter(line, _) => *line,
+        }
+    }
+}
+
+/// Asks the geocoder for the local names of countries and locations that don't have one,
+/// then shows the changes to odyssey.yaml and writes them once they are confirmed.
+pub fn enrich(
+    config: &Config,
+    cca3: &BTreeMap<String, String>,
+    shapes: &BTreeMap<String, CountryShape>,
+    config_path: &Path,
+    geocodes_path: &Path,
+) -> Result<(), Error> {
+    let geocoder = config.geocoder.build()?;
+    let mut geocodes = GeocodeCache::load(geocodes_path)?;
+
+    let mut proposals = Vec::new();
+    for (country, details) in &config.places {
+        let country_name = country.name();
+        let shape = shapes.get(&config.code(country, cca3)?);
+        if details.local.is_none() {
+            let local = geocode::native_name(
+                geocoder.as_ref(),
+                &Search::Country(&country_name),
+                &country_name,
+                shape,
+                &mut geocodes,
+            )?;
+            geocodes.save(geocodes_path)?;
+            let local = local.and_then(|local| checked(&local, &country_name));
+            if let Some(local) = local.filter(|local| *local != country_name) {
+                proposals.push(Proposal {
+                    country: country.clone(),
+                    location: None,
+                    local,
+                });
+            }
+        }
+        for (location, place) in &details.locations {
+            // City-states take the country's local name.
+            if place.local.is_some() || location.key() == country.to_string() {
+                continue;
+            }
+            let query = match &place.query {
+                Some(query) => Search::Text(query),
+                None => Search::Structured {
+                    city: &place.name,
+                    country: &country_name,
+                    country_code: shape.and_then(|shape| shape.iso_a2.as_deref()),
+                },
+            };
+            let local =
+                geocode::native_name(geocoder.as_ref(), &query, &place.name, shape, &mut geocodes)?;
+            geocodes.save(geocodes_path)?;
+            let local = local.and_then(|local| checked(&local, &place.name));
+            // Places already called the same in English, like Stockholm, need no local name.
+            if let Some(local) = local.filter(|local| *local != place.name) {
+                proposals.push(Proposal {
+                    country: country.clone(),
+                    location: Some(location.clone()),
+                    local,
+                });
+            }
+        }
+    }
+
+    if proposals.is_empty() {
+        println!("Every place has a local name, or the geocoder knows none.");
+        return Ok(());
+    }
+
+    let text = fs::read_to_string(config_path)?;
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let mut edits = edits(&lines, &proposals)?;
+    edits.sort_by_key(Edit::line);
+
+    for edit in &edits {
+        match edit {
+            Edit::Replace(line, new) => {
+                println!("{}:{}", config_path.display(), line + 1);
+                println!("-{}", lines[*line]);
+                println!("+{}", new);
+            }
+            Edit::InsertAfter(line, new) => {
+                println!("{}:{}", config_path.display(), line + 2);
+                println!(" {}", lines[*line]);
+                println!("+{}", new);
+            }
+        }
+    }
+
+    print!(
+        "Write {} local names to {}? [y/N] ",
+        edits.len(),
+        config_path.display()
+    );
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if !["y", "yes"].contains(&answer.trim().to_lowercase().as_str()) {
+        println!("Left {} unchanged.", config_path.display());
+        return Ok(());
+    }
+
+    // From the bottom up, so the line numbers of the remaining edits still hold.
+    for edit in edits.into_iter().rev() {
+        match edit {
+            Edit::Replace(line, new) => lines[line] = new,
+            Edit::InsertAfter(line, new) => lines.insert(line + 1, new),
+        }
+    }
+    let mut updated = lines.join("\n");
+    if text.ends_with('\n') {
+        updated.push('\n');
+    }
+    fs::write(config_path, updated)?;
+    println!("Updated {}.", config_path.display());
+    Ok(())
+}
+
+/// Puts a geocoder's name through the same checks as those in odyssey.yaml,
+/// as the map data it comes from is typed by hand too.
+fn checked(local: &str, place: &str) -> Option<String> {
+    let mut warnings = Vec::new();
+    let parsed = LocalName::parse(local, &mut warnings);
+    for warning in warnings {
+        println!("Warning: {:?} for {} {}", local, place, warning);
+    }
+    match parsed {
+        Ok(name) => Some(name.text),
+        Err(problem) => {
+            println!("Skipping {:?} for {}, as it {}", local, place, problem);
+            None
+        }
+    }
+}
+
+/// Works out the line changes for each proposal. odyssey.yaml is edited as text rather than
+/// reserialised, so its comments and layout survive.
+fn edits(lines: &[String], proposals: &[Proposal]) -> Result<Vec<Edit>, Error> {
+    let (countries, locations) = entries(lines);
+    proposals
+        .iter()
+        .map(|proposal| {
+            let value = yaml_scalar(&proposal.local)?;
+            let country = countries.get(&proposal.country.to_string());
+            let entry = match &proposal.location {
+                None => country,
+                Some(location) => {
+                    locations.get(&(proposal.country.to_string(), location.key().to_string()))
+                }
+            }
+            .ok_or_else(|| {
+                failure::err_msg(format!(
+                    "Could not find {} in the configuration",
+                    proposal
+                        .location
+                        .as_ref()
+                        .map_or(proposal.country.to_string(), Location::to_string)
+                ))
+            })?;
+
+            let line = &lines[entry.line];
+            let indent = indentation(line);
+            Ok(match (&proposal.location, entry.children) {
+                (None, children) => {
+                    let child = children.unwrap_or(indent + 2);
+                    Edit::InsertAfter(entry.line, format!("{:2$}Local: {}", "", value, child))
+                }
+                (Some(_), Some(child)) => {
+                    Edit::InsertAfter(entry.line, format!("{:2$}Local: {}", "", value, child))
+                }
+                (Some(location), None) => {
+                    // Keep any comment trailing the bare key.
+                    let rest = line.split_once(':').map_or("", |(_, rest)| rest).trim();
+                    let comment = if rest.starts_with('#') {
+                        format!(" {}", rest)
+                    } else {
+                        String::new()
+                    };
+                    Edit::Replace(
+                        entry.line,
+                        format!("{:4$}{}: {}{}", "", location.key(), value, comment, indent),
+                    )
+                }
+            })
+        })
+        .collect()
+}
+
+/// Finds the country and location keys in the `places` section.
+fn entries(lines: &[String]) -> (BTreeMap<String, Entry>, BTreeMap<(String, String), Entry>) {
+    let mut countries = BTreeMap::new();
+    let mut locations = BTreeMap::new();
+
+    let significant: Vec<(usize, usize, &str)> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(number, line)| (number, indentation(line), line.trim()))
+        .collect();
+
+    let mut in_places = false;
+    let mut country_indent = None;
+    let mut location_indent = None;
+    let mut country = String::new();
+    for (index, &(number, indent, content)) in significant.iter().enumerate() {
+        if indent == 0 {
+            in_places = content == "places:";
+            continue;
+        }
+        if !in_places {
+            continue;
+        }
+        let key = match content.find(':') {
+            Some(colon) => content[..colon].trim().to_string(),
+            None => continue,
+        };
+        let children = significant
+            .get(index + 1)
+            .map(|&(_, next, _)| next)
+            .filter(|&next| next > indent);
+
+        if *country_indent.get_or_insert(indent) == indent {
+            country = key.clone();
+            countries.insert(
+                key,
+                Entry {
+                    line: number,
+                    children,
+                },
+            );
+        } else if *location_indent.get_or_insert(indent) == indent {
+            locations.insert(
+                (country.clone(), key),
+                Entry {
+                    line: number,
+                    children,
+                },
+            );
+        }
+    }
+    (countries, locations)
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Quotes a name only if YAML would otherwise read it as something else.
+fn yaml_scalar(value: &str) -> Result<String, Error> {
+    let yaml = serde_yaml::to_string(value)?;
+    Ok(yaml.trim_start_matches("---").trim().to_string())
+}
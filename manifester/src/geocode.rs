@@ -0,0 +1,809 @@
+use crate::cache;
+use failure::Error;
+use reqwest::header::{RETRY_AFTER, USER_AGENT};
+use reqwest::StatusCode;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use url::Url;
+
+const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const NAME: &str = env!("CARGO_PKG_NAME");
+
+/// Nominatim's usage policy allows one request a second from each client.
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Raw geocoder responses keyed by their query, so a world build can be repeated
+/// offline and places aren't looked up again when cities.json is regenerated.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GeocodeCache {
+    pub responses: BTreeMap<String, serde_json::Value>,
+}
+
+impl GeocodeCache {
+    pub fn load(path: &Path) -> Result<GeocodeCache, Error> {
+        cache::load(path)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        cache::save(self, path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Nominatim,
+    Photon,
+    /// Never goes online: places must be in the geocode cache, or the given file.
+    Offline,
+}
+
+/// How places are geocoded, read from the `geocoder` section of odyssey.yaml.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeocoderSettings {
+    pub backend: Backend,
+    /// Defaults to the public server of the backend, set it to use a self-hosted one.
+    pub endpoint: Option<String>,
+    /// Defaults to the manifester's name, version and authors, as Nominatim asks for.
+    pub user_agent: Option<String>,
+    /// Responses in the format of the geocode cache, for the offline backend.
+    pub file: Option<PathBuf>,
+    /// How many times a failed request is retried, waiting twice as long each time.
+    pub retries: u32,
+}
+
+impl Default for GeocoderSettings {
+    fn default() -> GeocoderSettings {
+        GeocoderSettings {
+            backend: Backend::Nominatim,
+            endpoint: None,
+            user_agent: None,
+            file: None,
+            retries: 4,
+        }
+    }
+}
+
+impl GeocoderSettings {
+    pub fn build(&self) -> Result<Box<dyn Geocoder>, Error> {
+        Ok(match self.backend {
+            Backend::Nominatim => Box::new(Nominatim {
+                http: self.http("https://nominatim.openstreetmap.org/")?,
+            }),
+            Backend::Photon => Box::new(Photon {
+                http: self.http("https://photon.komoot.io/")?,
+            }),
+            Backend::Offline => Box::new(Offline {
+                responses: match &self.file {
+                    Some(file) => {
+                        let buffer = File::open(file).map_err(|err| {
+                            failure::err_msg(format!("{}: {}", file.display(), err))
+                        })?;
+                        serde_json::from_reader::<_, GeocodeCache>(buffer)?.responses
+                    }
+                    None => BTreeMap::new(),
+                },
+            }),
+        })
+    }
+
+    fn http(&self, default_endpoint: &str) -> Result<Http, Error> {
+        let endpoint = self
+            .endpoint
+            .as_ref()
+            .map_or(default_endpoint, String::as_str);
+        // Without the trailing slash, joining the search path would replace the last segment.
+        let endpoint = if endpoint.ends_with('/') {
+            Url::parse(endpoint)?
+        } else {
+            Url::parse(&format!("{}/", endpoint))?
+        };
+        Ok(Http {
+            client: reqwest::Client::new(),
+            endpoint,
+            user_agent: self
+                .user_agent
+                .clone()
+                .unwrap_or_else(|| format!("{} v{} - {}", NAME, VERSION, AUTHORS)),
+            retries: self.retries,
+            interval: REQUEST_INTERVAL,
+            last_request: Cell::new(None),
+        })
+    }
+}
+
+/// A geocoding service. Responses are cached raw, so each backend parses its own.
+pub trait Geocoder {
+    /// Asks the service, returning its response untouched.
+    fn fetch(&self, query: &Search) -> Result<serde_json::Value, Error>;
+
+    /// The candidate GeoJSON positions in a response, best first.
+    fn positions(&self, response: &serde_json::Value) -> Result<Vec<[f64; 2]>, Error>;
+
+    /// Where the response to a query is kept in the geocode cache.
+    fn cache_key(&self, query: &Search) -> String {
+        query.key()
+    }
+
+    /// Asks the service for the best match only, along with its name in each language.
+    fn fetch_names(&self, query: &Search) -> Result<serde_json::Value, Error>;
+
+    /// The name a place goes by in its own language, if the response has one.
+    fn native_name(&self, response: &serde_json::Value) -> Option<String>;
+}
+
+pub struct Nominatim {
+    http: Http,
+}
+
+#[derive(Deserialize, Debug)]
+struct LatLon {
+    lat: String,
+    lon: String,
+}
+
+impl Geocoder for Nominatim {
+    fn fetch(&self, query: &Search) -> Result<serde_json::Value, Error> {
+        let mut params = query.params();
+        params.push(("format", "jsonv2".to_string()));
+        // A few candidates, so one inside the country can be picked if the best match isn't.
+        params.push(("limit", "5".to_string()));
+        self.http.get("search", &params)
+    }
+
+    fn positions(&self, response: &serde_json::Value) -> Result<Vec<[f64; 2]>, Error> {
+        nominatim_positions(response)
+    }
+
+    fn fetch_names(&self, query: &Search) -> Result<serde_json::Value, Error> {
+        let mut params = query.params();
+        params.push(("format", "jsonv2".to_string()));
+        params.push(("namedetails", "1".to_string()));
+        params.push(("limit", "1".to_string()));
+        self.http.get("search", &params)
+    }
+
+    fn native_name(&self, response: &serde_json::Value) -> Option<String> {
+        nominatim_native_name(response)
+    }
+}
+
+/// OpenStreetMap's untranslated `name` tag is the one used on the ground.
+fn nominatim_native_name(response: &serde_json::Value) -> Option<String> {
+    response[0]["namedetails"]["name"]
+        .as_str()
+        .map(str::to_string)
+}
+
+fn nominatim_positions(response: &serde_json::Value) -> Result<Vec<[f64; 2]>, Error> {
+    serde_json::from_value::<Vec<LatLon>>(response.clone())?
+        .iter()
+        .map(|result| Ok([result.lon.parse::<f64>()?, result.lat.parse::<f64>()?]))
+        .collect()
+}
+
+pub struct Photon {
+    http: Http,
+}
+
+#[derive(Deserialize, Debug)]
+struct PhotonResponse {
+    features: Vec<PhotonFeature>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PhotonFeature {
+    geometry: PhotonGeometry,
+}
+
+#[derive(Deserialize, Debug)]
+struct PhotonGeometry {
+    coordinates: [f64; 2],
+}
+
+impl Photon {
+    /// Photon has no structured search, so those are sent as text.
+    fn text(query: &Search) -> String {
+        match query {
+            Search::Text(text) => text.to_string(),
+            Search::Structured { city, country, .. } => format!("{}, {}", city, country),
+            Search::Country(country) => country.to_string(),
+        }
+    }
+}
+
+impl Geocoder for Photon {
+    fn fetch(&self, query: &Search) -> Result<serde_json::Value, Error> {
+        self.http.get(
+            "api",
+            &[("q", Photon::text(query)), ("limit", "5".to_string())],
+        )
+    }
+
+    fn positions(&self, response: &serde_json::Value) -> Result<Vec<[f64; 2]>, Error> {
+        Ok(serde_json::from_value::<PhotonResponse>(response.clone())?
+            .features
+            .iter()
+            .map(|feature| feature.geometry.coordinates)
+            .collect())
+    }
+
+    /// Photon answers in GeoJSON, so its responses are kept apart from Nominatim's.
+    fn cache_key(&self, query: &Search) -> String {
+        format!("photon:{}", query.key())
+    }
+
+    /// Photon only gives one name, which is the local one when asked for the `default` language.
+    fn fetch_names(&self, query: &Search) -> Result<serde_json::Value, Error> {
+        self.http.get(
+            "api",
+            &[
+                ("q", Photon::text(query)),
+                ("lang", "default".to_string()),
+                ("limit", "1".to_string()),
+            ],
+        )
+    }
+
+    fn native_name(&self, response: &serde_json::Value) -> Option<String> {
+        response["features"][0]["properties"]["name"]
+            .as_str()
+            .map(str::to_string)
+    }
+}
+
+/// Answers from a file of Nominatim responses, or fails.
+pub struct Offline {
+    responses: BTreeMap<String, serde_json::Value>,
+}
+
+impl Offline {
+    fn answer(&self, key: &str) -> Result<serde_json::Value, Error> {
+        self.responses.get(key).cloned().ok_or_else(|| {
+            failure::err_msg(format!(
+                "{} has not been geocoded before, and the geocoder is offline",
+                key
+            ))
+        })
+    }
+}
+
+impl Geocoder for Offline {
+    fn fetch(&self, query: &Search) -> Result<serde_json::Value, Error> {
+        self.answer(&query.key())
+    }
+
+    fn positions(&self, response: &serde_json::Value) -> Result<Vec<[f64; 2]>, Error> {
+        nominatim_positions(response)
+    }
+
+    fn fetch_names(&self, query: &Search) -> Result<serde_json::Value, Error> {
+        self.answer(&names_key(self, query))
+    }
+
+    fn native_name(&self, response: &serde_json::Value) -> Option<String> {
+        nominatim_native_name(response)
+    }
+}
+
+/// A client shared by the online backends. Requests are spaced out by `REQUEST_INTERVAL`,
+/// and those that fail for reasons that may pass are retried with exponential backoff.
+struct Http {
+    client: reqwest::Client,
+    endpoint: Url,
+    user_agent: String,
+    retries: u32,
+    /// The least time between requests, and the first wait before a retry.
+    interval: Duration,
+    last_request: Cell<Option<Instant>>,
+}
+
+impl Http {
+    fn get(&self, path: &str, params: &[(&str, String)]) -> Result<serde_json::Value, Error> {
+        let url = Url::parse_with_params(self.endpoint.join(path)?.as_str(), params)?;
+        let mut backoff = self.interval;
+        let mut attempt = 0;
+        loop {
+            if let Some(last) = self.last_request.get() {
+                let elapsed = last.elapsed();
+                if elapsed < self.interval {
+                    thread::sleep(self.interval - elapsed);
+                }
+            }
+            let response = self
+                .client
+                .get(url.clone())
+                .header(USER_AGENT, self.user_agent.as_str())
+                .send();
+            self.last_request.set(Some(Instant::now()));
+
+            let (problem, retry_after) = match response {
+                Ok(mut res) if res.status().is_success() => return Ok(res.json()?),
+                Ok(res) => {
+                    let status = res.status();
+                    if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+                        return Err(failure::err_msg(format!("{} answered {}", url, status)));
+                    }
+                    let retry_after = res
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse::<u64>().ok())
+                        .map(Duration::from_secs);
+                    (status.to_string(), retry_after)
+                }
+                Err(err) => (err.to_string(), None),
+            };
+            if attempt >= self.retries {
+                return Err(failure::err_msg(format!(
+                    "{} failed {} times, last with {}",
+                    url,
+                    attempt + 1,
+                    problem
+                )));
+            }
+            let wait = retry_after.map_or(backoff, |after| after.max(backoff));
+            eprintln!("{} failed with {}, retrying in {:?}", url, problem, wait);
+            thread::sleep(wait);
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// A query for the geocoder.
+pub enum Search<'a> {
+    /// Free text, such as `"Perth, Australia"`.
+    Text(&'a str),
+    /// Nominatim's structured search, optionally restricted to an ISO 3166-1 alpha-2 code.
+    Structured {
+        city: &'a str,
+        country: &'a str,
+        country_code: Option<&'a str>,
+    },
+    /// A structured search for the country itself.
+    Country(&'a str),
+}
+
+impl<'a> Search<'a> {
+    fn params(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Search::Text(query) => vec![("q", query.to_string())],
+            Search::Structured {
+                city,
+                country,
+                country_code,
+            } => {
+                let mut params = vec![("city", city.to_string()), ("country", country.to_string())];
+                if let Some(code) = country_code {
+                    params.push(("countrycodes", code.to_lowercase()));
+                }
+                params
+            }
+            Search::Country(country) => vec![("country", country.to_string())],
+        }
+    }
+
+    /// Free text searches are cached under the text itself, structured ones under their parameters.
+    fn key(&self) -> String {
+        match self {
+            Search::Text(query) => query.to_string(),
+            Search::Structured { .. } | Search::Country(_) => self
+                .params()
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<String>>()
+                .join("&"),
+        }
+    }
+}
+
+/// Finds the candidate positions for a search, asking the geocoder only if it isn't cached.
+pub fn search(
+    geocoder: &dyn Geocoder,
+    query: &Search,
+    cache: &mut GeocodeCache,
+) -> Result<Vec<[f64; 2]>, Error> {
+    let key = geocoder.cache_key(query);
+    if !cache.responses.contains_key(&key) {
+        let response = geocoder.fetch(query)?;
+        cache.responses.insert(key.clone(), response);
+    }
+    geocoder.positions(&cache.responses[&key])
+}
+
+/// Responses with name details answer a different request, so they are cached apart.
+fn names_key(geocoder: &dyn Geocoder, query: &Search) -> String {
+    format!("names:{}", geocoder.cache_key(query))
+}
+
+/// Finds what the best match for a search is called in its own language, asking the
+/// geocoder only if it isn't cached. As in `locate`, a match outside the country's outline
+/// is some other place of the same name, so it has none.
+pub fn native_name(
+    geocoder: &dyn Geocoder,
+    query: &Search,
+    place: &str,
+    shape: Option<&CountryShape>,
+    cache: &mut GeocodeCache,
+) -> Result<Option<String>, Error> {
+    let key = names_key(geocoder, query);
+    if !cache.responses.contains_key(&key) {
+        let response = geocoder.fetch_names(query)?;
+        cache.responses.insert(key.clone(), response);
+    }
+    let response = &cache.responses[&key];
+    if let Some(shape) = shape {
+        match geocoder.positions(response)?.first() {
+            Some(&position) if !shape.contains(position) => {
+                println!(
+                    "Skipping the local name found for {}, as it belongs to a place outside the country at {:?}",
+                    place, position
+                );
+                return Ok(None);
+            }
+            _ => {}
+        }
+    }
+    Ok(geocoder.native_name(response))
+}
+
+/// How far, in degrees, a result may lie outside its country's outline and still be accepted.
+/// The outlines in countries.json are coarse, so coastal places often fall just outside.
+const BORDER_TOLERANCE: f64 = 0.5;
+/// Countries too small for an outline are a single point; archipelagos can spread far from it.
+const POINT_TOLERANCE: f64 = 3.0;
+
+/// Geocodes a place and checks the result lies within its country. If the free text search
+/// only finds places elsewhere, it is retried as a structured search restricted to the country.
+/// Returns a GeoJSON position.
+pub fn locate(
+    geocoder: &dyn Geocoder,
+    text: &str,
+    city: &str,
+    country: &str,
+    shape: Option<&CountryShape>,
+    cache: &mut GeocodeCache,
+) -> Result<[f64; 2], Error> {
+    let shape = match shape {
+        Some(shape) => shape,
+        None => {
+            println!(
+                "No outline of {} in countries.json, so {} can't be checked",
+                country, city
+            );
+            let results = search(geocoder, &Search::Text(text), cache)?;
+            return results.first().cloned().ok_or_else(|| {
+                failure::err_msg(format!("Search for {} did not find coordinates", text))
+            });
+        }
+    };
+
+    let structured = Search::Structured {
+        city,
+        country,
+        country_code: shape.iso_a2.as_deref(),
+    };
+    let mut rejected: Vec<[f64; 2]> = Vec::new();
+    for query in &[Search::Text(text), structured] {
+        for position in search(geocoder, query, cache)? {
+            if shape.contains(position) {
+                return Ok(position);
+            }
+            rejected.push(position);
+        }
+    }
+    Err(failure::err_msg(if rejected.is_empty() {
+        format!("Search for {} did not find coordinates", text)
+    } else {
+        format!(
+            "Search for {} only found places outside {}: {:?}. Give it a Query, or Lat and Lon, in the configuration",
+            text, country, rejected
+        )
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ShapeCollection {
+    features: Vec<ShapeFeature>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShapeFeature {
+    properties: ShapeProperties,
+    geometry: Shape,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShapeProperties {
+    su_a3: String,
+    iso_a2: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "coordinates")]
+enum Shape {
+    Point([f64; 2]),
+    Polygon(Vec<Vec<[f64; 2]>>),
+    MultiPolygon(Vec<Vec<Vec<[f64; 2]>>>),
+}
+
+/// A country's outline from countries.json.
+#[derive(Debug)]
+pub struct CountryShape {
+    /// Natural Earth writes `-99` for countries without a code, those are dropped.
+    pub iso_a2: Option<String>,
+    shape: Shape,
+}
+
+impl CountryShape {
+    /// Whether a GeoJSON position lies in the country, or close enough to its outline.
+    pub fn contains(&self, point: [f64; 2]) -> bool {
+        let polygons = match &self.shape {
+            Shape::Point(centre) => return distance(*centre, point) <= POINT_TOLERANCE,
+            Shape::Polygon(rings) => vec![rings],
+            Shape::MultiPolygon(polygons) => polygons.iter().collect(),
+        };
+        polygons.iter().any(|rings| {
+            let inside = match rings.split_first() {
+                Some((outer, holes)) => {
+                    in_ring(outer, point) && !holes.iter().any(|hole| in_ring(hole, point))
+                }
+                None => false,
+            };
+            inside
+                || rings
+                    .iter()
+                    .any(|ring| ring_distance(ring, point) <= BORDER_TOLERANCE)
+        })
+    }
+}
+
+/// Country outlines keyed by `su_a3`, the code `Config::code` looks up.
+pub fn load_shapes(path: &Path) -> Result<BTreeMap<String, CountryShape>, Error> {
+    let buffer = File::open(path)?;
+    let collection: ShapeCollection = serde_json::from_reader(io::BufReader::new(buffer))?;
+    Ok(collection
+        .features
+        .into_iter()
+        .map(|feature| {
+            let iso_a2 = feature.properties.iso_a2.filter(|code| code.len() == 2);
+            (
+                feature.properties.su_a3,
+                CountryShape {
+                    iso_a2,
+                    shape: feature.geometry,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Even-odd ray casting, treating longitude and latitude as planar.
+fn in_ring(ring: &[[f64; 2]], point: [f64; 2]) -> bool {
+    let [x, y] = point;
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (a[1] > y) != (b[1] > y) && x < (b[0] - a[0]) * (y - a[1]) / (b[1] - a[1]) + a[0] {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// The distance in degrees from a point to the nearest edge of a ring.
+fn ring_distance(ring: &[[f64; 2]], point: [f64; 2]) -> f64 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| {
+            let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+            let length = dx * dx + dy * dy;
+            let t = if length > 0.0 {
+                (((point[0] - a[0]) * dx + (point[1] - a[1]) * dy) / length).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            distance([a[0] + t * dx, a[1] + t * dy], point)
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+fn distance(a: [f64; 2], b: [f64; 2]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// Serves each canned response to one connection in turn, handing back the requests.
+    fn stub(responses: Vec<String>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|response| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let read = stream.read(&mut buffer).unwrap();
+                        if read == 0 {
+                            break;
+                        }
+                        request.extend_from_slice(&buffer[..read]);
+                    }
+                    stream.write_all(response.as_bytes()).unwrap();
+                    String::from_utf8(request).unwrap()
+                })
+                .collect()
+        });
+        (format!("http://{}", address), server)
+    }
+
+    fn response(status: &str, headers: &[&str], body: &str) -> String {
+        let mut response = format!("HTTP/1.1 {}\r\n", status);
+        for header in headers {
+            response.push_str(&format!("{}\r\n", header));
+        }
+        response.push_str(&format!(
+            "Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+        response
+    }
+
+    fn http(endpoint: &str, retries: u32) -> Http {
+        let settings = GeocoderSettings {
+            endpoint: Some(endpoint.to_string()),
+            user_agent: Some("odyssey-test (test@example.com)".to_string()),
+            retries,
+            ..GeocoderSettings::default()
+        };
+        let mut http = settings.http("https://unused.example/").unwrap();
+        http.interval = Duration::from_millis(20);
+        http
+    }
+
+    /// The first line of a request, such as `GET /search?q=Perth HTTP/1.1`.
+    fn request_line(request: &str) -> &str {
+        request.lines().next().unwrap()
+    }
+
+    const NOMINATIM_PERTH: &str = r#"[{"lat":"-31.95","lon":"115.86","namedetails":{"name":"Perth","name:en":"Perth"}},{"lat":"56.39","lon":"-3.43"}]"#;
+    const PHOTON_PERTH: &str = r#"{"type":"FeatureCollection","features":[{"type":"Feature","geometry":{"type":"Point","coordinates":[115.86,-31.95]},"properties":{"name":"Perth"}}]}"#;
+
+    #[test]
+    fn nominatim_joins_the_endpoint_and_sends_the_user_agent() {
+        let (endpoint, server) = stub(vec![response("200 OK", &[], NOMINATIM_PERTH)]);
+        let nominatim = Nominatim {
+            http: http(&format!("{}/nominatim", endpoint), 0),
+        };
+        let response = nominatim.fetch(&Search::Text("Perth, Australia")).unwrap();
+        let requests = server.join().unwrap();
+
+        assert_eq!(
+            request_line(&requests[0]),
+            "GET /nominatim/search?q=Perth%2C+Australia&format=jsonv2&limit=5 HTTP/1.1"
+        );
+        assert!(requests[0]
+            .to_lowercase()
+            .contains("\r\nuser-agent: odyssey-test (test@example.com)\r\n"));
+        assert_eq!(
+            nominatim.positions(&response).unwrap(),
+            vec![[115.86, -31.95], [-3.43, 56.39]]
+        );
+    }
+
+    #[test]
+    fn nominatim_structured_search_and_native_name() {
+        let (endpoint, server) = stub(vec![response("200 OK", &[], NOMINATIM_PERTH)]);
+        let nominatim = Nominatim {
+            http: http(&format!("{}/", endpoint), 0),
+        };
+        let query = Search::Structured {
+            city: "Perth",
+            country: "Australia",
+            country_code: Some("AU"),
+        };
+        let response = nominatim.fetch_names(&query).unwrap();
+        let requests = server.join().unwrap();
+
+        assert_eq!(
+            request_line(&requests[0]),
+            "GET /search?city=Perth&country=Australia&countrycodes=au&format=jsonv2&namedetails=1&limit=1 HTTP/1.1"
+        );
+        assert_eq!(nominatim.native_name(&response), Some("Perth".to_string()));
+        assert_eq!(nominatim.native_name(&serde_json::json!([])), None);
+    }
+
+    #[test]
+    fn photon_parses_geojson() {
+        let (endpoint, server) = stub(vec![
+            response("200 OK", &[], PHOTON_PERTH),
+            response("200 OK", &[], PHOTON_PERTH),
+        ]);
+        let photon = Photon {
+            http: http(&endpoint, 0),
+        };
+        let query = Search::Structured {
+            city: "Perth",
+            country: "Australia",
+            country_code: None,
+        };
+        let response = photon.fetch(&query).unwrap();
+        let names = photon.fetch_names(&query).unwrap();
+        let requests = server.join().unwrap();
+
+        assert_eq!(
+            request_line(&requests[0]),
+            "GET /api?q=Perth%2C+Australia&limit=5 HTTP/1.1"
+        );
+        assert_eq!(
+            request_line(&requests[1]),
+            "GET /api?q=Perth%2C+Australia&lang=default&limit=1 HTTP/1.1"
+        );
+        assert_eq!(photon.positions(&response).unwrap(), vec![[115.86, -31.95]]);
+        assert_eq!(photon.native_name(&names), Some("Perth".to_string()));
+    }
+
+    #[test]
+    fn too_many_requests_waits_as_long_as_asked() {
+        let (endpoint, server) = stub(vec![
+            response("429 Too Many Requests", &["Retry-After: 1"], ""),
+            response("200 OK", &[], NOMINATIM_PERTH),
+        ]);
+        let nominatim = Nominatim {
+            http: http(&endpoint, 1),
+        };
+        let started = Instant::now();
+        let response = nominatim.fetch(&Search::Text("Perth")).unwrap();
+
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(server.join().unwrap().len(), 2);
+        assert_eq!(nominatim.positions(&response).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn server_errors_back_off_then_give_up() {
+        let (endpoint, server) = stub(vec![
+            response("503 Service Unavailable", &[], ""),
+            response("502 Bad Gateway", &[], ""),
+            response("500 Internal Server Error", &[], ""),
+        ]);
+        let nominatim = Nominatim {
+            http: http(&endpoint, 2),
+        };
+        let started = Instant::now();
+        let err = nominatim.fetch(&Search::Text("Perth")).unwrap_err();
+
+        // Waits of 20ms, then 40ms.
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        assert_eq!(server.join().unwrap().len(), 3);
+        assert!(err.to_string().contains("failed 3 times, last with 500"));
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let (endpoint, server) = stub(vec![response("400 Bad Request", &[], "")]);
+        let nominatim = Nominatim {
+            http: http(&endpoint, 4),
+        };
+        let err = nominatim.fetch(&Search::Text("Perth")).unwrap_err();
+
+        assert_eq!(server.join().unwrap().len(), 1);
+        assert!(err.to_string().contains("400"));
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use unicode_bidi::{bidi_class, BidiClass};
+use unicode_normalization::UnicodeNormalization;
+
+/// Letters with a compatibility decomposition that are nonetheless the usual spelling,
+/// such as the Armenian ligature in Երևան.
+const COMPATIBLE_LETTERS: &[char] = &['\u{0587}'];
+
+/// Which way a local name is written, so the site can isolate right-to-left names
+/// from the English text around them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    LeftToRight,
+    RightToLeft,
+}
+
+/// Matches the `Direction` constructors in Manifest.elm.
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Direction::LeftToRight => "LeftToRight",
+            Direction::RightToLeft => "RightToLeft",
+        })
+    }
+}
+
+/// A place's name in its own language, in Unicode normalisation form C.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalName {
+    pub text: String,
+    pub direction: Direction,
+}
+
+impl fmt::Display for LocalName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl LocalName {
+    /// Normalises a name as written in odyssey.yaml. Names that would show up wrong, or
+    /// differently in different fonts, are refused; oddities that may be deliberate are
+    /// added to `warnings`.
+    pub fn parse(raw: &str, warnings: &mut Vec<String>) -> Result<LocalName, String> {
+        if raw.trim() != raw {
+            return Err("starts or ends with a space".to_string());
+        }
+        if let Some(c) = raw.chars().find(|&c| is_invisible(c)) {
+            return Err(format!(
+                "contains the invisible character U+{:04X}",
+                c as u32
+            ));
+        }
+
+        let text: String = raw.nfc().collect();
+        let compatible: String = text
+            .chars()
+            .flat_map(|c| {
+                if COMPATIBLE_LETTERS.contains(&c) {
+                    vec![c]
+                } else {
+                    c.nfkc().collect()
+                }
+            })
+            .nfc()
+            .collect();
+        if text != compatible {
+            if text.chars().any(is_presentation_form) {
+                return Err(format!(
+                    "is written in presentation forms, which are only meant for display; use {:?}",
+                    compatible
+                ));
+            }
+            warnings.push(format!(
+                "contains compatibility characters, perhaps {:?} is meant",
+                compatible
+            ));
+        }
+
+        let scripts: BTreeSet<&str> = text.chars().filter_map(script).collect();
+        if scripts.len() > 1 && !is_single_writing_system(&scripts) {
+            warnings.push(format!(
+                "mixes scripts: {}",
+                scripts.into_iter().collect::<Vec<&str>>().join(", ")
+            ));
+        }
+
+        // The first strongly directional character decides, as it does for a paragraph.
+        let direction = text
+            .chars()
+            .map(bidi_class)
+            .find_map(|class| match class {
+                BidiClass::L => Some(Direction::LeftToRight),
+                BidiClass::R | BidiClass::AL => Some(Direction::RightToLeft),
+                _ => None,
+            })
+            .unwrap_or(Direction::LeftToRight);
+
+        Ok(LocalName { text, direction })
+    }
+}
+
+/// Control and formatting characters that can't be seen, apart from the zero width
+/// (non-)joiners that Persian and the Indic scripts need. Other spaces than the plain one
+/// are included, as they are indistinguishable from it.
+fn is_invisible(c: char) -> bool {
+    match c {
+        '\u{200C}' | '\u{200D}' => false,
+        '\u{00AD}'
+        | '\u{034F}'
+        | '\u{061C}'
+        | '\u{115F}'
+        | '\u{1160}'
+        | '\u{180E}'
+        | '\u{200B}'..='\u{200F}'
+        | '\u{202A}'..='\u{202E}'
+        | '\u{2060}'..='\u{206F}'
+        | '\u{3164}'
+        | '\u{FE00}'..='\u{FE0F}'
+        | '\u{FEFF}'
+        | '\u{FFA0}'
+        | '\u{FFF9}'..='\u{FFFB}' => true,
+        _ => c.is_control() || (c.is_whitespace() && c != ' '),
+    }
+}
+
+/// Glyph variants kept in Unicode for compatibility with old encodings, such as the
+/// contextual shapes of Arabic letters and full width Latin.
+fn is_presentation_form(c: char) -> bool {
+    matches!(
+        c,
+        '\u{FB00}'..='\u{FDFF}'
+            | '\u{FE10}'..='\u{FE1F}'
+            | '\u{FE30}'..='\u{FE4F}'
+            | '\u{FE50}'..='\u{FEFE}'
+            | '\u{FF00}'..='\u{FFEF}'
+    )
+}
+
+/// Han is used together with kana in Japanese, with Hangul in Korean,
+/// and with Bopomofo in Taiwan.
+fn is_single_writing_system(scripts: &BTreeSet<&str>) -> bool {
+    [
+        &["Han", "Hiragana", "Katakana"][..],
+        &["Han", "Hangul"][..],
+        &["Han", "Bopomofo"][..],
+    ]
+    .iter()
+    .any(|system| scripts.iter().all(|script| system.contains(script)))
+}
+
+/// The script of a letter, by the Unicode blocks place names are written in.
+/// Digits, punctuation and combining marks are shared by all scripts, so have none.
+fn script(c: char) -> Option<&'static str> {
+    Some(match c {
+        'A'..='Z' | 'a'..='z' | '\u{00AA}' | '\u{00BA}' => "Latin",
+        '\u{00C0}'..='\u{024F}' if c != '\u{00D7}' && c != '\u{00F7}' => "Latin",
+        '\u{1E00}'..='\u{1EFF}' | '\u{2C60}'..='\u{2C7F}' | '\u{A720}'..='\u{A7FF}' => "Latin",
+        '\u{0370}'..='\u{03FF}' | '\u{1F00}'..='\u{1FFF}' => "Greek",
+        '\u{0400}'..='\u{052F}' | '\u{1C80}'..='\u{1C8F}' | '\u{A640}'..='\u{A69F}' => "Cyrillic",
+        '\u{0531}'..='\u{058F}' => "Armenian",
+        '\u{05D0}'..='\u{05FF}' => "Hebrew",
+        '\u{0620}'..='\u{064A}' | '\u{066E}'..='\u{06D5}' | '\u{0750}'..='\u{077F}' => "Arabic",
+        '\u{08A0}'..='\u{08C9}' | '\u{FB50}'..='\u{FDFF}' | '\u{FE70}'..='\u{FEFE}' => "Arabic",
+        '\u{0900}'..='\u{097F}' => "Devanagari",
+        '\u{0980}'..='\u{09FF}' => "Bengali",
+        '\u{0A00}'..='\u{0A7F}' => "Gurmukhi",
+        '\u{0A80}'..='\u{0AFF}' => "Gujarati",
+        '\u{0B00}'..='\u{0B7F}' => "Oriya",
+        '\u{0B80}'..='\u{0BFF}' => "Tamil",
+        '\u{0C00}'..='\u{0C7F}' => "Telugu",
+        '\u{0C80}'..='\u{0CFF}' => "Kannada",
+        '\u{0D00}'..='\u{0D7F}' => "Malayalam",
+        '\u{0D80}'..='\u{0DFF}' => "Sinhala",
+        '\u{0E00}'..='\u{0E7F}' => "Thai",
+        '\u{0E80}'..='\u{0EFF}' => "Lao",
+        '\u{0F00}'..='\u{0FFF}' => "Tibetan",
+        '\u{1000}'..='\u{109F}' => "Myanmar",
+        '\u{10A0}'..='\u{10FF}' | '\u{1C90}'..='\u{1CBF}' => "Georgian",
+        '\u{1100}'..='\u{11FF}' | '\u{3131}'..='\u{318E}' | '\u{AC00}'..='\u{D7AF}' => "Hangul",
+        '\u{1200}'..='\u{137F}' => "Ethiopic",
+        '\u{1780}'..='\u{17FF}' => "Khmer",
+        '\u{1800}'..='\u{18AF}' => "Mongolian",
+        '\u{3041}'..='\u{309F}' => "Hiragana",
+        '\u{30A0}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' | '\u{FF66}'..='\u{FF9F}' => "Katakana",
+        '\u{3105}'..='\u{312F}' => "Bopomofo",
+        '\u{3005}' | '\u{3007}' | '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' => "Han",
+        '\u{F900}'..='\u{FAFF}' | '\u{20000}'..='\u{3134F}' => "Han",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> (Result<LocalName, String>, Vec<String>) {
+        let mut warnings = Vec::new();
+        let parsed = LocalName::parse(raw, &mut warnings);
+        (parsed, warnings)
+    }
+
+    #[test]
+    fn presentation_forms_are_refused_with_the_plain_spelling() {
+        // Qatar as odyssey.yaml once had it, in the isolated and final forms of its letters.
+        let (parsed, _) = parse("\u{FED6}\u{FEC3}\u{FEAD}");
+        assert_eq!(
+            parsed.unwrap_err(),
+            "is written in presentation forms, which are only meant for display; use \"قطر\""
+        );
+    }
+
+    #[test]
+    fn the_armenian_ech_yiwn_ligature_is_a_letter() {
+        let (parsed, warnings) = parse("Երևան");
+        assert_eq!(parsed.unwrap().text, "Երևան");
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn invisible_characters_are_refused_but_joiners_are_not() {
+        for c in &['\u{200B}', '\u{200E}', '\u{202E}', '\u{2066}'] {
+            let (parsed, _) = parse(&format!("Ma{}lm", c));
+            assert_eq!(
+                parsed.unwrap_err(),
+                format!("contains the invisible character U+{:04X}", *c as u32)
+            );
+        }
+        // Persian for Tehran's Mehrabad uses a zero width non-joiner.
+        let (parsed, warnings) = parse("مهر\u{200C}آباد");
+        assert!(parsed.is_ok());
+        assert!(warnings.is_empty());
+        let (parsed, _) = parse("क्\u{200D}ष");
+        assert!(parsed.is_ok());
+    }
+
+    #[test]
+    fn surrounding_spaces_are_refused() {
+        for raw in &[" Wien", "Wien "] {
+            let (parsed, _) = parse(raw);
+            assert_eq!(parsed.unwrap_err(), "starts or ends with a space");
+        }
+    }
+
+    #[test]
+    fn arabic_and_hebrew_are_right_to_left() {
+        for raw in &["الدوحة", "ירושלים"] {
+            let (parsed, _) = parse(raw);
+            assert_eq!(parsed.unwrap().direction, Direction::RightToLeft);
+        }
+        let (parsed, _) = parse("Москва");
+        assert_eq!(parsed.unwrap().direction, Direction::LeftToRight);
+    }
+
+    #[test]
+    fn mixed_scripts_warn_unless_they_are_one_writing_system() {
+        // A Latin "o" among Cyrillic letters.
+        let (parsed, warnings) = parse("Мoсква");
+        assert!(parsed.is_ok());
+        assert_eq!(warnings, vec!["mixes scripts: Cyrillic, Latin".to_string()]);
+
+        let (parsed, warnings) = parse("東京タワー");
+        assert!(parsed.is_ok());
+        assert!(warnings.is_empty());
+    }
+}
@@ -1,4 +1,4 @@
-#![recursion_limit = "1024"]
+extern crate chrono;
 extern crate failure;
 extern crate globwalk;
 extern crate image;
@@ -8,36 +8,167 @@ extern crate num_cpus;
 extern crate rayon;
 extern crate reqwest;
 #[macro_use]
-extern crate enum_derive;
-#[macro_use]
-extern crate macro_attr;
-#[macro_use]
 extern crate serde_derive;
+#[cfg(feature = "avif")]
+extern crate ravif;
 extern crate serde_json;
 extern crate serde_yaml;
+extern crate sha2;
+extern crate strsim;
+extern crate structopt;
+extern crate unicode_bidi;
+extern crate unicode_normalization;
 extern crate url;
 extern crate url_serde;
-
+extern crate webp;
+
+mod attribution;
+mod cache;
+mod config;
+mod countries;
+mod enrich;
+mod geocode;
+mod local;
+mod thumbs;
+mod topology;
+
+use attribution::{write_attribution, Attributions};
+use cache::{BuildCache, ImageHeader};
+use config::{Config, Country, Location, LocationDetails};
 use failure::Error;
+use geocode::GeocodeCache;
 use globwalk::DirEntry;
-use image::FilterType::Lanczos3;
-use image::GenericImageView;
 use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::header::USER_AGENT;
-use std::collections::BTreeMap;
-use std::fs::{File, OpenOptions};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::File;
 use std::io::{Read, Write};
-use std::iter::FromIterator;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
-use std::time::Duration;
-use std::{fmt, thread};
-use url::Url;
+use structopt::StructOpt;
+
+/// Builds the world map and gallery manifest for Odyssey.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "manifester")]
+struct Opt {
+    #[structopt(flatten)]
+    paths: Paths,
+    #[structopt(subcommand)]
+    stage: Stage,
+}
+
+#[derive(Debug, StructOpt)]
+struct Paths {
+    /// Places and trips configuration
+    #[structopt(
+        long = "config",
+        default_value = "odyssey.yaml",
+        parse(from_os_str),
+        raw(global = "true")
+    )]
+    config: PathBuf,
+    /// Rights attribution configuration
+    #[structopt(
+        long = "attribution",
+        default_value = "attribution.yaml",
+        parse(from_os_str),
+        raw(global = "true")
+    )]
+    attribution: PathBuf,
+    /// Directory holding cca3.json, countries.json, cities.json, trips.json and the build cache
+    #[structopt(
+        long = "world",
+        default_value = "world",
+        parse(from_os_str),
+        raw(global = "true")
+    )]
+    world: PathBuf,
+    /// Root of the image gallery
+    #[structopt(
+        long = "gallery",
+        default_value = "../dist/gallery/",
+        parse(from_os_str),
+        raw(global = "true")
+    )]
+    gallery: PathBuf,
+    /// Output path of the world topology
+    #[structopt(
+        long = "topology",
+        default_value = "../dist/assets/world.json",
+        parse(from_os_str),
+        raw(global = "true")
+    )]
+    topology: PathBuf,
+    /// Output path of the generated Elm manifest
+    #[structopt(
+        long = "elm",
+        default_value = "../src/Manifest.elm",
+        parse(from_os_str),
+        raw(global = "true")
+    )]
+    elm: PathBuf,
+}
+
+impl Paths {
+    fn cca3(&self) -> PathBuf {
+        self.world.join("cca3.json")
+    }
+
+    fn countries(&self) -> PathBuf {
+        self.world.join("countries.json")
+    }
+
+    fn cities(&self) -> PathBuf {
+        self.world.join("cities.json")
+    }
+
+    fn trips(&self) -> PathBuf {
+        self.world.join("trips.json")
+    }
+
+    fn cache(&self) -> PathBuf {
+        self.world.join("derivatives.json")
+    }
 
-static NOMINATIM_ENDPOINT: &str = "http://nominatim.openstreetmap.org";
-const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
-const VERSION: &str = env!("CARGO_PKG_VERSION");
-const NAME: &str = env!("CARGO_PKG_NAME");
+    fn geocodes(&self) -> PathBuf {
+        self.world.join("geocodes.json")
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Stage {
+    /// Geocode new places, then rebuild cities.json, trips.json and the world topology
+    #[structopt(name = "world")]
+    World,
+    /// Write Manifest.elm from the configuration and gallery
+    #[structopt(name = "manifest")]
+    Manifest,
+    /// Generate thumbnails and blurs for new or changed images
+    #[structopt(name = "thumbs")]
+    Thumbs,
+    /// Write rights metadata from the attribution file into each image
+    #[structopt(name = "attribute")]
+    Attribute,
+    /// Validate the configuration and gallery without writing anything
+    #[structopt(name = "check")]
+    Check,
+    /// Report images whose rights metadata is missing or disagrees with the attribution files
+    #[structopt(name = "audit-rights")]
+    AuditRights {
+        /// Print the report as JSON
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Propose local names for places without one, and add them to the configuration once confirmed
+    #[structopt(name = "enrich")]
+    Enrich,
+    /// Run every stage: world, attribute, thumbs and manifest
+    #[structopt(name = "all")]
+    All,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CountryCodes {
@@ -78,44 +209,6 @@ mod codes {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    places: BTreeMap<Country, BTreeMap<Location, Option<String>>>,
-    trips: Vec<Trip>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct Attribution {
-    marked: bool,
-    usage_terms: String,
-    #[serde(with = "url_serde")]
-    web_statement: Url,
-    #[serde(with = "url_serde")]
-    license: Url,
-    #[serde(with = "url_serde")]
-    more_permissions: Url,
-    #[serde(with = "url_serde")]
-    attribution_url: Url,
-    attribution_name: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Trip {
-    name: String,
-    description: String,
-    cities: Vec<Location>,
-    dates: Vec<String>,
-}
-
-impl Trip {
-    fn id_string(&self) -> String {
-        let mut id = self.description.to_string();
-        id.retain(|c| c != ' ' && c != '/');
-        id
-    }
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 struct FeatureCollection {
     #[serde(rename = "type")]
@@ -131,13 +224,19 @@ struct Feature {
     geometry: Geometry,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct Properties {
+    /// The location's key in odyssey.yaml, which the name need not resemble.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
     name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     localname: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     country: Option<String>,
+    /// The `Query` the place was geocoded with, so it is looked up again when that changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    query: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -154,127 +253,79 @@ enum Coordinates {
     LineString(Vec<Vec<f32>>),
 }
 
-#[derive(Deserialize, Debug)]
-struct LatLon {
-    lat: String,
-    lon: String,
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 struct LocationInformation {
     id: Location,
     name: String,
+    /// The name, with the country added if another location has the same name.
+    label: String,
     country: Country,
     coordinates: Vec<f32>,
 }
 
-fn get_query_string(params: Vec<(&str, &str)>) -> String {
-    let pairs: Vec<String> = params
-        .into_iter()
-        .map(|(k, v)| format!("{}={}", k, v))
-        .collect();
-    pairs.join("&")
-}
-
-fn search(place_name: &str) -> Result<LatLon, Error> {
-    let params = vec![("format", "jsonv2"), ("q", place_name), ("limit", "1")];
-    let query_string = get_query_string(params);
-    let url = format!("{}/search?{}", NOMINATIM_ENDPOINT, query_string);
-    let client = reqwest::Client::new();
-    let mut res = client
-        .get(&url)
-        .header(USER_AGENT, format!("{} v{} - {}", NAME, VERSION, AUTHORS))
-        .send()?;
-    let mut results = res.json::<Vec<LatLon>>()?;
-    results.reverse();
-    let first = results.pop().ok_or_else(|| {
-        failure::err_msg(format!(
-            "Search for {} did not find coordinates",
-            place_name
-        ))
-    })?;
-    Ok(first)
-}
-
-fn to_location_identfier_string(from: &str) -> String {
-    let mut identifier = from.clone().to_string();
-    identifier.retain(|c| c != ' ' && c != '_');
-    if identifier == "Singapore" || identifier == "HongKong" {
-        identifier.push_str("City");
-    }
-    identifier
+/// How cities.json differs from the configuration.
+struct Reconciliation<'a> {
+    /// Locations without a feature, which need geocoding.
+    new: Vec<&'a Location>,
+    /// Features of locations that were given a new identifier, by index.
+    renamed: Vec<(usize, &'a Location, &'a LocationDetails)>,
+    /// Features of locations that are no longer configured, by index.
+    orphans: Vec<usize>,
 }
 
-fn get_new_places(
+fn reconcile<'a>(
     cities: &FeatureCollection,
-    config: &Config,
+    config: &'a Config,
     cca3: &BTreeMap<String, String>,
-) -> (Vec<Country>, Vec<Location>) {
-    let cities_country_codes = cities
-        .features
-        .iter()
-        .map(|f| f.properties.country.clone().unwrap())
-        .collect::<Vec<String>>();
-    let mut cities_countries: Vec<String> = Vec::new();
-    for c in cities_country_codes {
-        for (name, code) in cca3 {
-            if c == *code {
-                let mut identifier = name.clone().to_string();
-                identifier.retain(|c| c != ' ');
-                cities_countries.push(identifier);
-                break;
-            }
-        }
-    }
-    let config_countries = config
-        .places
-        .iter()
-        .map(|(name, _)| name)
-        .cloned()
-        .collect::<Vec<Country>>();
-    let mut new_countries: Vec<Country> = Vec::new();
-    for ctry in config_countries {
-        if !cities_countries.contains(&ctry.to_string()) {
-            new_countries.push(ctry);
-        }
-    }
-    let cities_locations = cities
-        .features
-        .iter()
-        .map(|f| to_location_identfier_string(&f.properties.name))
-        .collect::<Vec<String>>();
-    let config_locations = config
-        .places
-        .iter()
-        .map(|(_, locations)| {
-            locations
+) -> Result<Reconciliation<'a>, Error> {
+    let mut claimed = vec![false; cities.features.len()];
+    let mut new = Vec::new();
+    let mut renamed = Vec::new();
+    for (country, details) in &config.places {
+        let code = config.code(country, cca3)?;
+        let find = |key: &str, name: Option<&str>| {
+            cities
+                .features
                 .iter()
-                .map(|(name, _)| name)
-                .cloned()
-                .collect::<Vec<Location>>()
-        })
-        .flatten()
-        .collect::<Vec<Location>>();
-    let mut new_locations: Vec<Location> = Vec::new();
-    for loc in config_locations {
-        if !cities_locations.contains(&loc.to_string()) && loc != Location::Local {
-            new_locations.push(loc);
+                .position(|f| f.is_place(&code, key, name))
+        };
+        for (location, place) in &details.locations {
+            if let Some(idx) = find(location.key(), Some(&place.name)) {
+                claimed[idx] = true;
+            } else if let Some(idx) = place.renamed_from.as_ref().and_then(|old| find(old, None)) {
+                claimed[idx] = true;
+                renamed.push((idx, location, place));
+            } else {
+                new.push(location);
+            }
         }
     }
-    (new_countries, new_locations)
+    let orphans = (0..claimed.len()).filter(|idx| !claimed[*idx]).collect();
+    Ok(Reconciliation {
+        new,
+        renamed,
+        orphans,
+    })
 }
 
-fn write_trip(config: &Config, features: &[Feature]) -> Result<(), Error> {
+fn write_trip(
+    config: &Config,
+    features: &[Feature],
+    cca3: &BTreeMap<String, String>,
+    trips_path: &Path,
+) -> Result<(), Error> {
     let mut trip_features: Vec<Feature> = Vec::new();
     for trip in &config.trips {
         let properties = Properties {
+            key: None,
             name: trip.name.clone(),
             localname: None,
             country: None,
+            query: None,
         };
         let mut coords: Vec<Vec<f32>> = Vec::new();
         for city in &trip.cities {
-            coords.push(city.feature_coordinates(&features)?);
+            coords.push(city.feature_coordinates(config, &features, cca3)?);
         }
         let geometry = Geometry {
             type_: "LineString".to_string(),
@@ -290,149 +341,216 @@ fn write_trip(config: &Config, features: &[Feature]) -> Result<(), Error> {
         type_: "FeatureCollection".to_string(),
         features: trip_features,
     };
-    let trips_buffer = File::create("world/trips.json")?;
+    let trips_buffer = File::create(trips_path)?;
     serde_json::to_writer(&trips_buffer, &trips)?;
     Ok(())
 }
 
-fn construct_world(
+fn read_cities(paths: &Paths) -> Result<FeatureCollection, Error> {
+    let cities_buffer = File::open(paths.cities())?;
+    let cities: FeatureCollection = serde_json::from_reader(&cities_buffer)?;
+    Ok(cities)
+}
+
+fn locations_information(
     config: &Config,
+    features: &[Feature],
     cca3: &BTreeMap<String, String>,
 ) -> Result<Vec<LocationInformation>, Error> {
-    let pause = Duration::from_secs(1);
-    let cities_buffer = OpenOptions::new()
-        .read(true)
-        .write(false) // Don't write unless needed
-        .create(false) //Fail if we need to create the file
-        .open("world/cities.json");
-
+    let names = config
+        .places
+        .values()
+        .flat_map(|details| details.locations.values())
+        .map(|place| place.name.as_str())
+        .collect::<Vec<&str>>();
     let mut locations_details: Vec<LocationInformation> = Vec::new();
-    match cities_buffer {
-        Ok(buffer) => {
-            // Add new info to cities.json
-            let mut cities: FeatureCollection = serde_json::from_reader(&buffer)?;
-            let (new_countries, new_locations) = get_new_places(&cities, config, cca3);
-            for (country, locations) in &config.places {
-                for (location, local_name) in
-                    locations.iter().filter(|(l, _)| **l != Location::Local)
-                {
-                    let coordinates = if new_locations.contains(location) {
-                        let coords = search(&format!("{}, {}", location.name(), country.name()))?;
-                        thread::sleep(pause); //We can't hammer the Nominatim server.
-
-                        let properties = Properties {
-                            name: location.name(),
-                            localname: local_name.to_owned(),
-                            country: Some(country.code(&cca3)?),
-                        };
-                        let coordinates =
-                            vec![coords.lon.parse::<f32>()?, coords.lat.parse::<f32>()?];
-                        let geometry = Geometry {
-                            type_: "Point".to_string(),
-                            coordinates: Coordinates::Point(coordinates.clone()),
-                        };
-
-                        println!("{} {:?}", location.to_string(), geometry.coordinates);
-                        cities.features.push(Feature {
-                            type_: "Feature".to_string(),
-                            properties,
-                            geometry,
-                        });
-                        coordinates
-                    } else {
-                        location.feature_coordinates(&cities.features)?
-                    };
-                    locations_details.push(LocationInformation {
-                        id: location.clone(),
-                        name: location.name(),
-                        country: country.clone(),
-                        coordinates,
-                    });
-                }
-            }
-            if new_countries.len() + new_locations.len() > 0 {
-                let cities_writer = OpenOptions::new()
-                    .write(true)
-                    .truncate(true) //We must truncate the file before writing the new data.
-                    .open("world/cities.json")?;
-                serde_json::to_writer(&cities_writer, &cities)?;
-            }
-
-            write_trip(&config, &cities.features)?;
+    for (country, details) in &config.places {
+        for (location, place) in &details.locations {
+            let shared = names.iter().filter(|name| **name == place.name).count() > 1;
+            locations_details.push(LocationInformation {
+                id: location.clone(),
+                name: place.name.clone(),
+                label: if shared {
+                    format!("{}, {}", place.name, country.name())
+                } else {
+                    place.name.clone()
+                },
+                country: country.clone(),
+                coordinates: location.feature_coordinates(config, features, cca3)?,
+            });
         }
+    }
+    Ok(locations_details)
+}
+
+/// Carries renamed places over to their new key, and drops those no longer configured.
+/// Returns whether cities.json changed.
+fn apply_renames_and_orphans(
+    cities: &mut FeatureCollection,
+    reconciliation: Reconciliation,
+) -> bool {
+    let mut changed = false;
+    for (idx, location, place) in reconciliation.renamed {
+        println!(
+            "{} renamed to {}",
+            cities.features[idx].properties.name, location
+        );
+        let properties = &mut cities.features[idx].properties;
+        properties.key = Some(location.key().to_string());
+        properties.name = place.name.clone();
+        changed = true;
+    }
+    for idx in reconciliation.orphans.into_iter().rev() {
+        let orphan = cities.features.remove(idx);
+        println!(
+            "{} is no longer configured, removed",
+            orphan.properties.name
+        );
+        changed = true;
+    }
+    changed
+}
+
+fn construct_world(
+    config: &Config,
+    cca3: &BTreeMap<String, String>,
+    paths: &Paths,
+) -> Result<Vec<LocationInformation>, Error> {
+    let mut cities = match read_cities(paths) {
+        Ok(cities) => cities,
         Err(_) => {
-            // Create a new cities.json
-            println!("No world/cities.json found, building one");
-            let mut features: Vec<Feature> = Vec::new();
-            for (country, locations) in &config.places {
-                for (location, local_name) in
-                    locations.iter().filter(|(l, _)| **l != Location::Local)
-                {
-                    let coords = search(&format!("{}, {}", location.name(), country.name()))?;
-                    thread::sleep(pause); //We can't hammer the Nominatim server.
-
-                    let properties = Properties {
-                        name: location.name(),
-                        localname: local_name.to_owned(),
-                        country: Some(country.code(&cca3)?),
-                    };
-                    let coordinates = vec![coords.lon.parse::<f32>()?, coords.lat.parse::<f32>()?];
+            println!("No {} found, building one", paths.cities().display());
+            FeatureCollection {
+                type_: "FeatureCollection".to_string(),
+                features: Vec::new(),
+            }
+        }
+    };
+    let mut geocodes = GeocodeCache::load(&paths.geocodes())?;
+    let shapes = geocode::load_shapes(&paths.countries())?;
+    let geocoder = config.geocoder.build()?;
+
+    let reconciliation = reconcile(&cities, config, cca3)?;
+    let mut changed = apply_renames_and_orphans(&mut cities, reconciliation);
+
+    // Add new info to cities.json, and update places whose name or coordinates changed in the config.
+    for (country, details) in &config.places {
+        let code = config.code(country, &cca3)?;
+        for (location, place) in &details.locations {
+            let properties = Properties {
+                key: Some(location.key().to_string()),
+                name: place.name.clone(),
+                localname: place.local.as_ref().map(|local| local.text.clone()),
+                country: Some(code.clone()),
+                query: match place.position {
+                    Some(_) => None,
+                    None => place.query.clone(),
+                },
+            };
+            let existing = cities
+                .features
+                .iter()
+                .position(|f| f.is_place(&code, location.key(), Some(&place.name)));
+            let lookup = existing.map_or(true, |idx| {
+                cities.features[idx].properties.query != properties.query
+            });
+            let position = match place.position {
+                Some(position) => Some([position.lon, position.lat]),
+                None if lookup => {
+                    let query = place
+                        .query
+                        .clone()
+                        .unwrap_or_else(|| format!("{}, {}", place.name, country.name()));
+                    let position = geocode::locate(
+                        geocoder.as_ref(),
+                        &query,
+                        &place.name,
+                        &country.name(),
+                        shapes.get(&code),
+                        &mut geocodes,
+                    );
+                    // Keep what has been looked up so far, even if a later search fails.
+                    geocodes.save(&paths.geocodes())?;
+                    Some(position?)
+                }
+                None => None,
+            };
+            match existing {
+                Some(idx) => {
+                    let feature = &mut cities.features[idx];
+                    if feature.properties != properties {
+                        println!("{} updated from the configuration", location);
+                        feature.properties = properties;
+                        changed = true;
+                    }
+                    if let Some(position) = position {
+                        let coordinates =
+                            Coordinates::Point(vec![position[0] as f32, position[1] as f32]);
+                        if feature.geometry.coordinates != coordinates {
+                            println!("{} moved to {:?}", location, coordinates);
+                            feature.geometry.coordinates = coordinates;
+                            changed = true;
+                        }
+                    }
+                }
+                None => {
+                    let position = position.ok_or_else(|| {
+                        failure::err_msg(format!("{} was not looked up", location))
+                    })?;
                     let geometry = Geometry {
                         type_: "Point".to_string(),
-                        coordinates: Coordinates::Point(coordinates.clone()),
+                        coordinates: Coordinates::Point(vec![
+                            position[0] as f32,
+                            position[1] as f32,
+                        ]),
                     };
-
-                    println!("{} {:?}", location.to_string(), geometry.coordinates);
-                    features.push(Feature {
+                    println!("{} {:?}", location, geometry.coordinates);
+                    cities.features.push(Feature {
                         type_: "Feature".to_string(),
                         properties,
                         geometry,
                     });
-                    locations_details.push(LocationInformation {
-                        id: location.clone(),
-                        name: location.name(),
-                        country: country.clone(),
-                        coordinates,
-                    });
+                    changed = true;
                 }
             }
-
-            write_trip(&config, &features)?;
-
-            let cities = FeatureCollection {
-                type_: "FeatureCollection".to_string(),
-                features,
-            };
-            let cities_create = File::create("world/cities.json")?;
-            serde_json::to_writer(&cities_create, &cities)?;
         }
-    };
+    }
+    if changed {
+        //We must truncate the file before writing the new data.
+        let cities_writer = File::create(paths.cities())?;
+        serde_json::to_writer(&cities_writer, &cities)?;
+    }
+
+    write_trip(&config, &cities.features, cca3, &paths.trips())?;
 
     println!("Building world.");
-    Command::new("topojson")
-        .arg("-o")
-        .arg("../dist/assets/world.json")
-        .arg("--id-property")
-        .arg("su_a3")
-        .arg("--properties")
-        .arg("name,localname,country")
-        .arg("--")
-        .arg("world/countries.json")
-        .arg("world/cities.json")
-        .arg("world/trips.json")
-        .status()?;
-    Ok(locations_details)
+    topology::write_topology(
+        &[&paths.countries(), &paths.cities(), &paths.trips()],
+        &paths.topology,
+    )?;
+    locations_information(config, &cities.features, cca3)
 }
 
 fn construct_manifest(
     config: &Config,
-    attrib: &Attribution,
     cca3: &BTreeMap<String, String>,
     locations_information: &[LocationInformation],
+    attributions: &Attributions,
+    images: &[DirEntry],
+    paths: &Paths,
 ) -> Result<(), Error> {
     println!("Building Manifest.");
-    let mut manifest = File::create("../src/Manifest.elm")?;
-    writeln!(manifest, "module Manifest exposing (Country(..), Date, Image, Location(..), Month(..), Trip(..), Year, countryId, countryList, countryLocalName, countryName, locationInformation, locationList, locationLocalName, manifest, stringToCountry, stringToLocation, stringToTrip, tripInformation, tripList)")?;
+    let mut manifest = File::create(&paths.elm)?;
+    writeln!(manifest, "module Manifest exposing (Country(..), Credit, Date, Direction(..), Image, LocalName, Location(..), Month(..), Trip(..), Year, countryId, countryList, countryLocalName, countryName, locationInformation, locationList, locationLocalName, manifest, stringToCountry, stringToLocation, stringToTrip, tripInformation, tripList)")?;
+
+    writeln!(manifest, "-- LOCAL NAMES")?;
+    writeln!(manifest, "type Direction")?;
+    writeln!(manifest, "    = LeftToRight")?;
+    writeln!(manifest, "    | RightToLeft")?;
+    writeln!(manifest, "type alias LocalName =")?;
+    writeln!(manifest, "    {{ name : String")?;
+    writeln!(manifest, "    , direction : Direction")?;
+    writeln!(manifest, "    }}")?;
 
     writeln!(manifest, "-- COUNTRIES")?;
     write_countries(&mut manifest, config, cca3)?;
@@ -444,12 +562,19 @@ fn construct_manifest(
     write_trips(&mut manifest, config)?;
 
     writeln!(manifest, "-- MANIFEST")?;
-    write_manifest(&mut manifest, attrib)?;
+    write_manifest(
+        &mut manifest,
+        config,
+        attributions,
+        images,
+        &paths.gallery,
+        &paths.cache(),
+    )?;
 
     Command::new("elm-format")
         .arg("--elm-version=0.19")
         .arg("--yes")
-        .arg("../src/Manifest.elm")
+        .arg(&paths.elm)
         .status()?;
     Ok(())
 }
@@ -488,7 +613,7 @@ fn write_countries(
     writeln!(manifest, "    case country of")?;
     for (cntry, _) in &config.places {
         writeln!(manifest, "        {} ->", cntry)?;
-        writeln!(manifest, "            \"{}\"", cntry.code(&cca3)?)?;
+        writeln!(manifest, "            \"{}\"", config.code(cntry, &cca3)?)?;
     }
 
     writeln!(manifest, "countryName : Country -> String")?;
@@ -509,13 +634,17 @@ fn write_countries(
     writeln!(manifest, "        _ ->")?;
     writeln!(manifest, "            Nothing")?;
 
-    writeln!(manifest, "countryLocalName : Country -> Maybe String")?;
+    writeln!(manifest, "countryLocalName : Country -> Maybe LocalName")?;
     writeln!(manifest, "countryLocalName country =")?;
     writeln!(manifest, "    case country of")?;
-    for (cntry, locations) in &config.places {
-        if let Some(Some(local)) = locations.get(&Location::Local) {
+    for (cntry, details) in &config.places {
+        if let Some(local) = &details.local {
             writeln!(manifest, "        {} ->", cntry)?;
-            writeln!(manifest, "            Just \"{}\"", local)?;
+            writeln!(
+                manifest,
+                "            Just {{ name = \"{}\", direction = {} }}",
+                local, local.direction
+            )?;
         };
     }
     writeln!(manifest, "        _ ->")?;
@@ -531,17 +660,10 @@ fn write_locations(
 ) -> Result<(), Error> {
     let mut config_locations = config
         .places
-        .iter()
-        .map(|(_, locations)| {
-            locations
-                .iter()
-                .map(|(name, loc)| (name.clone(), loc.clone()))
-                .collect::<Vec<(Location, Option<String>)>>()
-        })
-        .flatten()
-        .filter(|(l, _)| *l != Location::Local)
-        .collect::<Vec<(Location, Option<String>)>>();
-    config_locations.sort();
+        .values()
+        .flat_map(|details| details.locations.iter())
+        .collect::<Vec<(&Location, &LocationDetails)>>();
+    config_locations.sort_by_key(|(loc, _)| *loc);
     writeln!(manifest, "type Location")?;
     let mut idx = 0;
     for (loc, _) in &config_locations {
@@ -569,20 +691,24 @@ fn write_locations(
     writeln!(manifest, "stringToLocation : String -> Maybe Location")?;
     writeln!(manifest, "stringToLocation location =")?;
     writeln!(manifest, "    case location of")?;
-    for (loc, _) in &config_locations {
-        writeln!(manifest, "        \"{}\" ->", loc.name())?;
-        writeln!(manifest, "            Just {}", loc)?;
+    for info in locations_information {
+        writeln!(manifest, "        \"{}\" ->", info.label)?;
+        writeln!(manifest, "            Just {}", info.id)?;
     }
     writeln!(manifest, "        _ ->")?;
     writeln!(manifest, "            Nothing")?;
 
-    writeln!(manifest, "locationLocalName : Location -> Maybe String")?;
+    writeln!(manifest, "locationLocalName : Location -> Maybe LocalName")?;
     writeln!(manifest, "locationLocalName location =")?;
     writeln!(manifest, "    case location of")?;
-    for (loc, local_name) in &config_locations {
-        if let Some(local) = local_name {
+    for (loc, place) in &config_locations {
+        if let Some(local) = &place.local {
             writeln!(manifest, "        {} ->", loc)?;
-            writeln!(manifest, "            Just \"{}\"", local)?;
+            writeln!(
+                manifest,
+                "            Just {{ name = \"{}\", direction = {} }}",
+                local, local.direction
+            )?;
         };
     }
     writeln!(manifest, "        _ ->")?;
@@ -590,6 +716,7 @@ fn write_locations(
 
     writeln!(manifest, "type alias LocationInformation =")?;
     writeln!(manifest, "    {{ name : String")?;
+    writeln!(manifest, "    , label : String")?;
     writeln!(manifest, "    , country : Country")?;
     writeln!(manifest, "    , coordinates : ( Float, Float )")?;
     writeln!(manifest, "    }}")?;
@@ -611,6 +738,7 @@ fn write_locations(
             .ok_or_else(|| failure::err_msg("No latitude value in coordinates"))?;
         writeln!(manifest, "    {} ->", info.id)?;
         writeln!(manifest, "    {{ name = \"{}\"", info.name)?;
+        writeln!(manifest, "    , label = \"{}\"", info.label)?;
         writeln!(manifest, "    , country = {}", info.country)?;
         writeln!(manifest, "    , coordinates = ( {:.3}, {:.3} )", lon, lat)?;
         writeln!(manifest, "    }}")?;
@@ -738,10 +866,56 @@ fn write_trips(manifest: &mut File, config: &Config) -> Result<(), Error> {
     Ok(())
 }
 
-fn write_manifest(manifest: &mut File, attrib: &Attribution) -> Result<(), Error> {
+/// An image's place in the `<year>/<month>/<country>/<location>` gallery hierarchy.
+struct GalleryPath<'a> {
+    name: String,
+    year: String,
+    month: Month,
+    location: &'a Location,
+}
+
+fn parse_gallery_path<'a>(
+    config: &'a Config,
+    gallery: &Path,
+    path: &Path,
+) -> Result<GalleryPath<'a>, Error> {
+    let mut path_iter = path.strip_prefix(gallery)?.iter().rev();
+
+    let name = path_iter
+        .next()
+        .and_then(|p| p.to_str())
+        .ok_or_else(|| failure::err_msg("File name unwrap issue."))?;
+    let location_str = path_iter
+        .next()
+        .and_then(|p| p.to_str())
+        .ok_or_else(|| failure::err_msg("Location unwrap issue."))?;
+    let country_str = path_iter
+        .next()
+        .and_then(|p| p.to_str())
+        .ok_or_else(|| failure::err_msg("Country unwrap issue."))?;
+    let location = config.gallery_location(country_str, location_str)?;
+    let month = path_iter
+        .next()
+        .and_then(|p| p.to_str())
+        .ok_or_else(|| failure::err_msg("Month unwrap issue."))?
+        .parse::<Month>()?;
+    let year = path_iter
+        .next()
+        .and_then(|p| p.to_str())
+        .ok_or_else(|| failure::err_msg("Year unwrap issue."))?;
+
+    Ok(GalleryPath {
+        name: name.to_string(),
+        year: year.to_string(),
+        month,
+        location,
+    })
+}
+
+fn gallery_images(gallery: &Path) -> Result<Vec<DirEntry>, Error> {
     // Ignore the thumbnails and blurs at this point. We will check for them later.
     let walker = globwalk::GlobWalkerBuilder::from_patterns(
-        "../dist/gallery/",
+        gallery,
         &["*.{png,jpg,jpeg,PNG,JPG,JPEG}", "!*_small*", "!*_blur*"],
     )
     .follow_links(true)
@@ -749,185 +923,418 @@ fn write_manifest(manifest: &mut File, attrib: &Attribution) -> Result<(), Error
     .into_iter()
     .filter_map(Result::ok)
     .collect::<Vec<DirEntry>>();
+    Ok(walker)
+}
 
-    let progcount = walker.len() as u64;
-    let bar = ProgressBar::new(progcount);
+fn progress_bar(len: usize) -> ProgressBar {
+    let bar = ProgressBar::new(len as u64);
     bar.set_style(
         ProgressStyle::default_bar()
             .template("[{elapsed_precise}] {bar:25.cyan/blue} {pos:>5}/{len:5} {msg}"),
     );
+    bar
+}
+
+/// Everything the manifest needs to know about one image.
+struct ManifestEntry<'a> {
+    /// Path relative to the gallery root.
+    key: String,
+    image: GalleryPath<'a>,
+    header: ImageHeader,
+    description: String,
+    /// Modern formats the thumbnail and blur are also available in.
+    formats: Vec<&'static str>,
+    thumbnails: Vec<thumbs::Thumbnail>,
+    /// Colours to paint while the thumbnail loads, written by the thumbs stage.
+    placeholder: Vec<String>,
+    /// Whether a current `_blur` image exists.
+    blur: bool,
+    credit: Credit,
+}
+
+/// Who to credit for an image, and under which licence.
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
+struct Credit {
+    author: String,
+    author_url: String,
+    license: String,
+}
+
+impl Credit {
+    /// Named after a hash of its contents, so adding a credit leaves the others' names alone.
+    fn name(&self) -> String {
+        let digest = Sha256::digest(
+            format!("{}\n{}\n{}", self.author, self.author_url, self.license).as_bytes(),
+        );
+        format!("credit{}", &format!("{:x}", digest)[..8])
+    }
+}
+
+fn manifest_entry<'a>(
+    config: &'a Config,
+    attributions: &Attributions,
+    cache: &BuildCache,
+    gallery: &Path,
+    file: &DirEntry,
+) -> Result<ManifestEntry<'a>, Error> {
+    // Grab the image's dimensions and capture time without decoding it.
+    let key = file
+        .path()
+        .strip_prefix(gallery)?
+        .to_string_lossy()
+        .into_owned();
+    let header = cache.header(&key, file.path())?;
+
+    // Get image decription if it exists, create file if not.
+    let mut description = String::new();
+    let _ = File::open(file.path().with_extension("desc"))
+        .or_else(|_| File::create(file.path().with_extension("desc")))
+        .and_then(|mut f| f.read_to_string(&mut description));
+
+    let image = parse_gallery_path(config, gallery, file.path())?;
+    let attrib = attributions.for_image(file.path())?;
+
+    Ok(ManifestEntry {
+        image,
+        description: description.trim().to_string(),
+        formats: thumbs::available_formats(file.path()),
+        thumbnails: thumbs::thumbnail_sizes(&config.thumbnails, header.width, header.height),
+        placeholder: cache
+            .sources
+            .get(&key)
+            .map(|entry| entry.placeholder.clone())
+            .unwrap_or_default(),
+        // Blurs left over from before they were switched off may be stale.
+        blur: config.thumbnails.blur && thumbs::has_blur(file.path()),
+        credit: Credit {
+            author: attrib.attribution_name,
+            author_url: attrib.attribution_url.into_string(),
+            license: attrib.license.into_string(),
+        },
+        key,
+        header,
+    })
+}
+
+/// Images are gathered in parallel, then listed in order of their directory relative to the
+/// gallery root (year, month, country and location). Within a location they are ordered by
+/// capture time, with undated images last, and finally by file name.
+fn write_manifest(
+    manifest: &mut File,
+    config: &Config,
+    attributions: &Attributions,
+    images: &[DirEntry],
+    gallery: &Path,
+    cache_path: &Path,
+) -> Result<(), Error> {
+    let mut cache = BuildCache::load(cache_path)?;
+    let bar = progress_bar(images.len());
+    // Build a manifest of all files. We do this entirely each time as descriptions or filenames may have changed.
+    let mut entries = images
+        .par_iter()
+        .map(|file| {
+            let entry = manifest_entry(config, attributions, &cache, gallery, file);
+            bar.inc(1);
+            entry
+        })
+        .collect::<Result<Vec<ManifestEntry>, Error>>()?;
+    bar.finish();
+    entries.sort_by(|a, b| {
+        let a_dir = Path::new(&a.key).parent();
+        let b_dir = Path::new(&b.key).parent();
+        a_dir
+            .cmp(&b_dir)
+            .then_with(|| {
+                a.header
+                    .captured
+                    .is_none()
+                    .cmp(&b.header.captured.is_none())
+            })
+            .then_with(|| a.header.captured.cmp(&b.header.captured))
+            .then_with(|| a.key.cmp(&b.key))
+    });
+
+    // Few images differ in credit, so each distinct one is written once and referred to by name.
+    let credits = entries
+        .iter()
+        .map(|entry| entry.credit.clone())
+        .collect::<BTreeSet<Credit>>()
+        .into_iter()
+        .map(|credit| {
+            let name = credit.name();
+            (credit, name)
+        })
+        .collect::<BTreeMap<Credit, String>>();
+    if credits.values().collect::<BTreeSet<&String>>().len() != credits.len() {
+        return Err(failure::err_msg(
+            "Two credits hash to the same name, change one of their authors slightly",
+        ));
+    }
+    writeln!(manifest, "type alias Credit =")?;
+    writeln!(manifest, "    {{ author : String")?;
+    writeln!(manifest, "    , authorUrl : String")?;
+    writeln!(manifest, "    , license : String")?;
+    writeln!(manifest, "    }}")?;
+    for (credit, name) in &credits {
+        writeln!(manifest, "{} : Credit", name)?;
+        writeln!(manifest, "{} =", name)?;
+        writeln!(
+            manifest,
+            "    Credit \"{}\" \"{}\" \"{}\"",
+            credit.author, credit.author_url, credit.license
+        )?;
+    }
 
     writeln!(manifest, "type alias Image =")?;
     writeln!(manifest, "    {{ file : String")?;
     writeln!(manifest, "    , date : Date")?;
+    writeln!(manifest, "    , captured : Maybe String")?;
     writeln!(manifest, "    , location : Location")?;
     writeln!(manifest, "    , aspectRatio : Float")?;
     writeln!(manifest, "    , description : String")?;
+    writeln!(manifest, "    , formats : List String")?;
+    writeln!(manifest, "    , thumbnails : List ( Int, Int )")?;
+    writeln!(manifest, "    , placeholder : List String")?;
+    writeln!(manifest, "    , blur : Bool")?;
+    writeln!(manifest, "    , credit : Credit")?;
     writeln!(manifest, "    }}")?;
 
     writeln!(manifest, "manifest : List Image")?;
     writeln!(manifest, "manifest =")?;
 
-    for (idx, file) in bar.wrap_iter(walker.iter().enumerate()) {
-        let bar_msg = file
-            .path()
-            .strip_prefix("../dist/gallery/")?
-            .to_str()
-            .unwrap_or_default();
-        if bar_msg.len() > 50 {
-            let msg = bar_msg.split('/').collect::<Vec<&str>>();
-            bar.set_message(&format!(".../.../{}", msg.last().unwrap()));
+    for (idx, entry) in entries.iter().enumerate() {
+        let captured = match &entry.header.captured {
+            Some(timestamp) => format!("(Just \"{}\")", timestamp),
+            None => "Nothing".to_string(),
+        };
+        let formats = if entry.formats.is_empty() {
+            "[]".to_string()
         } else {
-            bar.set_message(&bar_msg);
-        }
-
-        // Add licensing metadata if needed.
-        if attrib.marked {
-            let meta = rexiv2::Metadata::new_from_path(&file.path())?;
-            //Blanket clear all xmp data. TODO: this needs a better solution.
-            meta.clear_xmp();
-            rexiv2::unregister_all_xmp_namespaces();
-            rexiv2::register_xmp_namespace("http://creativecommons.org/ns#/", "cc")?;
-
-            let marked = match attrib.marked {
-                true => "True",
-                false => "False",
-            };
-
-            meta.set_tag_string("Xmp.xmpRights.Marked", marked)?;
-            meta.set_tag_string("Xmp.xmpRights.UsageTerms", &attrib.usage_terms)?;
-            meta.set_tag_string("Xmp.dc.rights", &attrib.usage_terms)?;
-            meta.set_tag_string("Xmp.xmpRights.WebStatement", attrib.web_statement.as_str())?;
-            meta.set_tag_string("Xmp.cc.license", attrib.license.as_str())?;
-            meta.set_tag_string("Xmp.cc.morePermissions", attrib.more_permissions.as_str())?;
-            meta.set_tag_string("Xmp.cc.attributionURL", attrib.attribution_url.as_str())?;
-            meta.set_tag_string("Xmp.cc.attributionName", &attrib.attribution_name)?;
-
-            meta.save_to_file(&file.path())?;
-        }
-
-        // Open image and grab its dimensions.
-        let img = image::open(&file.path())?;
-        let (width, height) = img.dimensions();
-        let ratio = width as f64 / height as f64;
-        let afile = file.clone();
-        rayon::spawn(move || {
-            // Generate a thumbnail and blur if they doesn't already exist.
-            let stem = afile
-                .path()
-                .file_stem()
-                .and_then(|p| p.to_str())
-                .expect("File stem unwrap issue.");
-            let ext = afile
-                .path()
-                .extension()
-                .and_then(|p| p.to_str())
-                .expect("Extension unwrap issue.");
-            let thumbnail = format!("{}_small.{}", stem, ext);
-            let blur = format!("{}_blur.{}", stem, ext);
-            let thumb_width = if ratio < 3.0 { 500 } else { 900 };
-            if !afile.path().with_file_name(&thumbnail).exists()
-                && !afile.path().with_file_name(&blur).exists()
-            {
-                let thumb = img.resize(thumb_width, 500, Lanczos3);
-                thumb
-                    .save(afile.path().with_file_name(thumbnail))
-                    .expect("Failed to save thumbnail.");
-                thumb
-                    .blur(30.0)
-                    .save(afile.path().with_file_name(blur))
-                    .expect("Failed to save blur.");
-            } else if !afile.path().with_file_name(&thumbnail).exists() {
-                img.resize(thumb_width, 500, Lanczos3)
-                    .save(afile.path().with_file_name(thumbnail))
-                    .expect("Failed to save thumbnail.");
-            } else if !afile.path().with_file_name(&blur).exists() {
-                img.resize(thumb_width, 500, Lanczos3)
-                    .blur(30.0)
-                    .save(afile.path().with_file_name(blur))
-                    .expect("Failed to save blur.");
-            }
-        });
-
-        // Get image decription if it exists, create file if not.
-        let mut description = String::new();
-        let _ = File::open(file.path().with_extension("desc"))
-            .or_else(|_| File::create(file.path().with_extension("desc")))
-            .and_then(|mut f| f.read_to_string(&mut description));
-
-        // Build a manifest of all files. We do this entirely each time as descriptions or filenames may have changed.
-        let mut path_iter = file.path().strip_prefix("../dist/gallery/")?.iter().rev();
-
-        let name = path_iter
-            .next()
-            .and_then(|p| p.to_str())
-            .ok_or_else(|| failure::err_msg("File name unwrap issue."))?;
-        let location_str = path_iter
-            .next()
-            .and_then(|p| p.to_str())
-            .ok_or_else(|| failure::err_msg("Location unwrap issue."))?;
-        let location = to_location_identfier_string(&location_str).parse::<Location>()?;
-        let _country = path_iter.next();
-        let month = path_iter
-            .next()
-            .and_then(|p| p.to_str())
-            .ok_or_else(|| failure::err_msg("Month unwrap issue."))?
-            .parse::<Month>()?;
-        let year = path_iter
-            .next()
-            .and_then(|p| p.to_str())
-            .ok_or_else(|| failure::err_msg("Year unwrap issue."))?;
-
+            format!("[ \"{}\" ]", entry.formats.join("\", \""))
+        };
+        let thumbnails = format!(
+            "[ {} ]",
+            entry
+                .thumbnails
+                .iter()
+                .map(|thumbnail| format!("( {}, {} )", thumbnail.size, thumbnail.width))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        let placeholder = if entry.placeholder.is_empty() {
+            "[]".to_string()
+        } else {
+            format!("[ \"{}\" ]", entry.placeholder.join("\", \""))
+        };
+        let blur = if entry.blur { "True" } else { "False" };
         if idx != 0 {
             write!(
                 manifest,
-                "    , Image \"{}\" (Date {} {:?}) {:?} {:.3} \"{}\"\n",
-                name,
-                year,
-                month,
-                location,
-                ratio,
-                description.trim()
+                "    , Image \"{}\" (Date {} {:?}) {} {} {:.3} \"{}\" {} {} {} {} {}\n",
+                entry.image.name,
+                entry.image.year,
+                entry.image.month,
+                captured,
+                entry.image.location,
+                entry.header.ratio(),
+                entry.description,
+                formats,
+                thumbnails,
+                placeholder,
+                blur,
+                credits[&entry.credit]
             )?;
         } else {
             write!(
                 manifest,
-                "    [ Image \"{}\" (Date {} {:?}) {:?} {:.3} \"{}\"\n",
-                name,
-                year,
-                month,
-                location,
-                ratio,
-                description.trim()
+                "    [ Image \"{}\" (Date {} {:?}) {} {} {:.3} \"{}\" {} {} {} {} {}\n",
+                entry.image.name,
+                entry.image.year,
+                entry.image.month,
+                captured,
+                entry.image.location,
+                entry.header.ratio(),
+                entry.description,
+                formats,
+                thumbnails,
+                placeholder,
+                blur,
+                credits[&entry.credit]
             )?;
         }
     }
     writeln!(manifest, "    ]")?;
-    bar.finish();
+
+    // Only keep headers of images that are still in the gallery.
+    cache.headers = entries
+        .into_iter()
+        .map(|entry| (entry.key, entry.header))
+        .collect();
+    cache.save(cache_path)?;
 
     Ok(())
 }
 
+/// Validates the configuration files and gallery layout without writing anything.
+fn check(config: &Config, cca3: &BTreeMap<String, String>, paths: &Paths) -> Result<(), Error> {
+    let mut problems: Vec<String> = Vec::new();
+    let shapes = geocode::load_shapes(&paths.countries())?;
+    for country in config.places.keys() {
+        match config.code(country, cca3) {
+            // An explicit code has nothing else to go on, so one without an outline is
+            // most likely mistyped.
+            Ok(code) if !shapes.contains_key(&code) && config.places[country].code.is_some() => {
+                problems.push(format!(
+                    "{} has the Code {}, which is not an su_a3 in {}",
+                    country,
+                    code,
+                    paths.countries().display()
+                ))
+            }
+            // Countries are drawn on the globe with their su_a3 as the id, so a code without
+            // an outline still works, but the country can't be highlighted.
+            Ok(code) if !shapes.contains_key(&code) => println!(
+                "Warning: {} ({}) has no outline in {}, so it can't be highlighted on the globe",
+                country,
+                code,
+                paths.countries().display()
+            ),
+            Ok(_) => {}
+            Err(err) => problems.push(err.to_string()),
+        }
+    }
+    let codes_found = problems.is_empty();
+    if let Err(err) = Attributions::load(&paths.attribution, &paths.gallery) {
+        problems.push(format!("{}: {}", paths.attribution.display(), err));
+    }
+    match read_cities(paths) {
+        // Places are matched to cities.json by their country's code.
+        Ok(_) if !codes_found => {}
+        Ok(cities) => {
+            let reconciliation = reconcile(&cities, config, cca3)?;
+            for location in reconciliation.new {
+                println!("{} will be added on the next world build.", location);
+            }
+            for (idx, location, _) in reconciliation.renamed {
+                println!(
+                    "{} will be renamed to {} on the next world build.",
+                    cities.features[idx].properties.name, location
+                );
+            }
+            for idx in reconciliation.orphans {
+                println!(
+                    "{} is no longer configured, and will be removed on the next world build.",
+                    cities.features[idx].properties.name
+                );
+            }
+        }
+        Err(_) => println!(
+            "No {} found, every location will be geocoded on the next world build.",
+            paths.cities().display()
+        ),
+    }
+    for file in gallery_images(&paths.gallery)? {
+        if let Err(err) = parse_gallery_path(config, &paths.gallery, file.path()) {
+            problems.push(format!("{}: {}", file.path().display(), err));
+        }
+    }
+
+    if problems.is_empty() {
+        println!("Configuration and gallery are valid.");
+        Ok(())
+    } else {
+        Err(failure::err_msg(format!(
+            "Found {} problems:\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        )))
+    }
+}
+
 fn main() -> Result<(), Error> {
+    let opt = Opt::from_args();
+    let paths = &opt.paths;
+
     rayon::ThreadPoolBuilder::new()
         .num_threads(num_cpus::get_physical())
         .build_global()?;
+    // Metadata is read and written from the pool, which gexiv2 must be prepared for.
+    rexiv2::initialize()?;
 
-    let config_file = File::open("odyssey.yaml")?;
-    let config: Config = serde_yaml::from_reader(config_file)?;
+    let config = Config::load(&paths.config)?;
 
-    let attribution_file = File::open("attribution.yaml")?;
-    let attrib: Attribution = serde_yaml::from_reader(attribution_file)?;
-
-    let cca3_file = File::open("world/cca3.json")?;
+    let cca3_file = File::open(paths.cca3())?;
     let cca3_read: CountryCodes = serde_json::from_reader(cca3_file)?;
     let cca3 = &cca3_read.codes;
 
-    let locations_information = construct_world(&config, &cca3)?;
-
-    construct_manifest(&config, &attrib, &cca3, &locations_information)?;
-
-    println!("World and Manifest builds complete.");
+    match opt.stage {
+        Stage::World => {
+            construct_world(&config, &cca3, paths)?;
+            println!("World build complete.");
+        }
+        Stage::Manifest => {
+            let cities = read_cities(paths).map_err(|err| {
+                failure::err_msg(format!(
+                    "Could not read {} ({}), run the world stage first",
+                    paths.cities().display(),
+                    err
+                ))
+            })?;
+            let locations_information = locations_information(&config, &cities.features, &cca3)?;
+            let attributions = Attributions::load(&paths.attribution, &paths.gallery)?;
+            let images = gallery_images(&paths.gallery)?;
+            construct_manifest(
+                &config,
+                &cca3,
+                &locations_information,
+                &attributions,
+                &images,
+                paths,
+            )?;
+            println!("Manifest build complete.");
+        }
+        Stage::Thumbs => thumbs::write_thumbnails(
+            &gallery_images(&paths.gallery)?,
+            &paths.gallery,
+            &paths.cache(),
+            &config.thumbnails,
+        )?,
+        Stage::Attribute => {
+            let attributions = Attributions::load(&paths.attribution, &paths.gallery)?;
+            write_attribution(&gallery_images(&paths.gallery)?, &attributions)?;
+        }
+        Stage::Check => check(&config, &cca3, paths)?,
+        Stage::AuditRights { json } => {
+            let attributions = Attributions::load(&paths.attribution, &paths.gallery)?;
+            attribution::audit_rights(
+                &gallery_images(&paths.gallery)?,
+                &paths.gallery,
+                &attributions,
+                json,
+            )?;
+        }
+        Stage::Enrich => {
+            let shapes = geocode::load_shapes(&paths.countries())?;
+            enrich::enrich(&config, cca3, &shapes, &paths.config, &paths.geocodes())?
+        }
+        Stage::All => {
+            let attributions = Attributions::load(&paths.attribution, &paths.gallery)?;
+            let locations_information = construct_world(&config, &cca3, paths)?;
+            let images = gallery_images(&paths.gallery)?;
+            write_attribution(&images, &attributions)?;
+            // Thumbnails go first, so the manifest can list the formats they were written in.
+            thumbs::write_thumbnails(&images, &paths.gallery, &paths.cache(), &config.thumbnails)?;
+            construct_manifest(
+                &config,
+                &cca3,
+                &locations_information,
+                &attributions,
+                &images,
+                paths,
+            )?;
+            println!("World and Manifest builds complete.");
+        }
+    }
 
     Ok(())
 }
@@ -979,222 +1386,32 @@ impl fmt::Display for Month {
     }
 }
 
-macro_attr! {
-    #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, EnumFromStr!, Serialize, Deserialize)]
-    enum Country {
-        Armenia,
-        Australia,
-        Austria,
-        China,
-        CzechRepublic,
-        Denmark,
-        Estonia,
-        FaeroeIslands,
-        Finland,
-        France,
-        Germany,
-        Greece,
-        HongKong,
-        Hungary,
-        Iceland,
-        Japan,
-        Latvia,
-        Netherlands,
-        Norway,
-        Poland,
-        Qatar,
-        Russia,
-        Singapore,
-        Slovakia,
-        Sweden,
-        Thailand,
-        Ukraine,
-        UnitedKingdom,
-        Vietnam,
-    }
-}
-
-impl fmt::Display for Country {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
-    }
-}
-
-impl Country {
-    fn name(&self) -> String {
-        let mut name: Vec<char> = Vec::new();
-        for (idx, c) in self.to_string().char_indices() {
-            if idx > 0 && c.is_uppercase() {
-                name.push(' ');
+impl Feature {
+    /// Whether this is the feature of a location, given its country code and key.
+    /// Features written before keys were recorded are recognised by their name, or by the
+    /// key that name would have been derived from.
+    fn is_place(&self, code: &str, key: &str, name: Option<&str>) -> bool {
+        self.properties.country.as_deref() == Some(code)
+            && match &self.properties.key {
+                Some(feature_key) => feature_key == key,
+                None => {
+                    Some(self.properties.name.as_str()) == name
+                        || self.properties.name.replace(' ', "") == key
+                }
             }
-            name.push(c);
-        }
-        String::from_iter(name)
-    }
-
-    fn code(&self, cca3: &BTreeMap<String, String>) -> Result<String, Error> {
-        let country_code = cca3.get(&self.name()).ok_or_else(|| {
-            failure::err_msg(format!("{} does not exist in cca3.json", self.name()))
-        })?;
-        Ok(country_code.to_string())
-    }
-}
-
-macro_attr! {
-    #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, EnumFromStr!, Serialize, Deserialize)]
-    enum Location {
-        Local,
-        Abisko,
-        Amsterdam,
-        Are,
-        Arjang,
-        Athens,
-        Auschwitz,
-        Ayutthaya,
-        BadBelzig,
-        Balestrand,
-        Bangkok,
-        BellsBeach,
-        Bengtsfors,
-        Bergen,
-        Berlin,
-        BlueMountains,
-        Bodo,
-        Bordoy,
-        Bratislava,
-        Bremen,
-        Brisbane,
-        Budapest,
-        CapeOtwayLighthouse,
-        Chernobyl,
-        CoffsHarbour,
-        Copenhagen,
-        Crete,
-        Doha,
-        Dronningmolle,
-        Exeter,
-        Eysturoy,
-        Fjaerland,
-        Flam,
-        Frankfurt,
-        Freiburg,
-        Geysir,
-        GoldCoast,
-        Gothenburg,
-        Goulburn,
-        HaLongBay,
-        Hamburg,
-        Hanoi,
-        Healesville,
-        Heidelberg,
-        Helsingborg,
-        Helsingor,
-        Helsinki,
-        Hestur,
-        Himeji,
-        Hiroshima,
-        HoChiMinhCity,
-        HongKongCity,
-        Jokulsarlon,
-        Jukkasjarvi,
-        Kanchanaburi,
-        Karlsruhe,
-        Katowice,
-        Kiev,
-        Kinnekulle,
-        Kiruna,
-        KoSamui,
-        KoTao,
-        Koyasan,
-        Krakow,
-        Kristiansund,
-        Kyoto,
-        London,
-        Lorne,
-        Lund,
-        Melbourne,
-        Mooroopna,
-        Munich,
-        MyallLakes,
-        Nikkaluokta,
-        Osaka,
-        Oslo,
-        Ostersund,
-        Paris,
-        Petergof,
-        Potsdam,
-        Prague,
-        Pripyat,
-        Pushkin,
-        Puttgarden,
-        Quanzhou,
-        Revsund,
-        Reykjavik,
-        Riga,
-        Rorvik,
-        Roskilde,
-        SaintPetersburg,
-        Salisbury,
-        Shanghai,
-        Shepparton,
-        SingaporeCity,
-        Skaftafell,
-        Skogarfoss,
-        Stockholm,
-        Stonehenge,
-        StoraKarlso,
-        Streymoy,
-        Svolvaer,
-        Sydney,
-        Tallinn,
-        TamborineMountain,
-        TheTwelveApostles,
-        Thingvellir,
-        Tokyo,
-        Torshavn,
-        Trollhattan,
-        Tromso,
-        Trondheim,
-        Trysil,
-        Umea,
-        Vagar,
-        Vidoy,
-        Vienna,
-        Vik,
-        Visby,
-        Warsaw,
-        Yass,
-        YellowMountain,
-        Yerevan,
-        Yering,
-    }
-}
-
-impl fmt::Display for Location {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
     }
 }
 
 impl Location {
-    fn name(&self) -> String {
-        let mut name: Vec<char> = Vec::new();
-        for (idx, c) in self.to_string().char_indices() {
-            if idx > 0 && c.is_uppercase() {
-                name.push(' ');
-            }
-            name.push(c);
-        }
-        let mut location_name = String::from_iter(name);
-        if location_name.ends_with("City") && *self != Location::HoChiMinhCity {
-            location_name.truncate(location_name.len() - 5);
-        }
-        location_name
-    }
-
-    fn feature_coordinates(&self, features: &[Feature]) -> Result<Vec<f32>, Error> {
+    fn feature_coordinates(
+        &self,
+        config: &Config,
+        features: &[Feature],
+        cca3: &BTreeMap<String, String>,
+    ) -> Result<Vec<f32>, Error> {
+        let code = config.code(self.country(), cca3)?;
         for feature in features {
-            if to_location_identfier_string(&feature.properties.name) == self.to_string() {
+            if feature.is_place(&code, self.key(), None) {
                 match &feature.geometry.coordinates {
                     Coordinates::Point(coords) => return Ok(coords.clone()),
                     _ => {
@@ -1212,3 +1429,72 @@ impl Location {
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn city(key: &str, name: &str) -> Feature {
+        Feature {
+            type_: "Feature".to_string(),
+            properties: Properties {
+                key: Some(key.to_string()),
+                name: name.to_string(),
+                localname: None,
+                country: Some("VNM".to_string()),
+                query: None,
+            },
+            geometry: Geometry {
+                type_: "Point".to_string(),
+                coordinates: Coordinates::Point(vec![106.7, 10.8]),
+            },
+        }
+    }
+
+    #[test]
+    fn credit_names_depend_only_on_the_credit() {
+        let credit = |author: &str, license: &str| Credit {
+            author: author.to_string(),
+            author_url: "https://example.com".to_string(),
+            license: license.to_string(),
+        };
+        let name = credit("Tim", "CC BY 4.0").name();
+        assert_eq!(name, credit("Tim", "CC BY 4.0").name());
+        assert_eq!(name.len(), "credit".len() + 8);
+        assert_ne!(name, credit("Tim", "CC BY-SA 4.0").name());
+        assert_ne!(name, credit("Ann", "CC BY 4.0").name());
+    }
+
+    #[test]
+    fn renamed_places_are_found_under_their_new_key() {
+        let config = Config::from_yaml(
+            "places:\n  Vietnam:\n    HoChiMinhCity:\n      RenamedFrom: Saigon\n    Hanoi:\ntrips: []\n",
+        )
+        .unwrap();
+        let cca3: BTreeMap<String, String> = vec![("Vietnam".to_string(), "VNM".to_string())]
+            .into_iter()
+            .collect();
+        let mut cities = FeatureCollection {
+            type_: "FeatureCollection".to_string(),
+            features: vec![
+                city("Saigon", "Saigon"),
+                city("Hanoi", "Hanoi"),
+                city("Hue", "Hue"),
+            ],
+        };
+
+        let reconciliation = reconcile(&cities, &config, &cca3).unwrap();
+        assert!(reconciliation.new.is_empty());
+        assert_eq!(reconciliation.renamed.len(), 1);
+        assert_eq!(reconciliation.orphans, vec![2]);
+        assert!(apply_renames_and_orphans(&mut cities, reconciliation));
+
+        // The lookup construct_world makes before geocoding finds the renamed feature.
+        assert_eq!(cities.features.len(), 2);
+        assert!(cities.features[0].is_place("VNM", "HoChiMinhCity", Some("Ho Chi Minh City")));
+        let again = reconcile(&cities, &config, &cca3).unwrap();
+        assert!(again.new.is_empty());
+        assert!(again.renamed.is_empty());
+        assert!(again.orphans.is_empty());
+    }
+}
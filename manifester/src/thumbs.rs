@@ -0,0 +1,439 @@
+use crate::cache::{self, BuildCache, CacheEntry, ImageHeader};
+use failure::Error;
+use globwalk::DirEntry;
+use image::FilterType::Lanczos3;
+use image::{DynamicImage, ImageOutputFormat};
+use rayon::prelude::*;
+use rexiv2::Orientation;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+/// How derivatives are made, read from the `thumbnails` section of odyssey.yaml.
+/// Changing any of these regenerates every derivative.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Edge of the box each thumbnail size is fitted into. The first size is written as
+    /// the `_small` file the site falls back to, the rest as `_small_{size}`.
+    pub sizes: Vec<u32>,
+    /// Images wider than this ratio are treated as panoramas.
+    pub panorama_ratio: f64,
+    /// How many times wider than tall a panorama's box is.
+    pub panorama_stretch: f64,
+    /// Whether to write `_blur` images. Without them the site paints the manifest's
+    /// placeholder colours instead, saving a download per image.
+    pub blur: bool,
+    pub blur_sigma: f32,
+    pub jpeg_quality: u8,
+    pub webp_quality: f32,
+    pub avif_quality: f32,
+    /// From 1 (slowest, smallest) to 10 (fastest).
+    pub avif_speed: u8,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            // The gallery rows are about 500px tall, so this covers screens up to 2x.
+            sizes: vec![500, 1000],
+            panorama_ratio: 3.0,
+            panorama_stretch: 1.8,
+            blur: false,
+            blur_sigma: 30.0,
+            // Matches the quality `DynamicImage::save` uses for JPEGs.
+            jpeg_quality: 75,
+            webp_quality: 75.0,
+            avif_quality: 60.0,
+            avif_speed: 6,
+        }
+    }
+}
+
+/// How many horizontal bands the placeholder gradient is made of.
+const PLACEHOLDER_BANDS: u32 = 4;
+
+/// One size of thumbnail written for a source.
+#[derive(Debug, Clone, Copy)]
+pub struct Thumbnail {
+    /// The configured size it was made for.
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Generates thumbnails and blurs in parallel, waiting for every job to finish.
+/// Sources are skipped if the build cache shows they and the settings are unchanged.
+/// Failures don't stop the other jobs, they are collected and reported once all are done.
+pub fn write_thumbnails(
+    images: &[DirEntry],
+    gallery: &Path,
+    cache_path: &Path,
+    settings: &Settings,
+) -> Result<(), Error> {
+    println!("Building thumbnails.");
+    let mut cache = BuildCache::load(cache_path)?;
+    let bar = crate::progress_bar(images.len());
+    let results = images
+        .par_iter()
+        .map(|file| {
+            let key = file
+                .path()
+                .strip_prefix(gallery)
+                .unwrap_or_else(|_| file.path())
+                .to_string_lossy()
+                .into_owned();
+            let result = cache
+                .header(&key, file.path())
+                .and_then(|header| derive(file.path(), &header, cache.sources.get(&key), settings));
+            bar.inc(1);
+            (key, result)
+        })
+        .collect::<Vec<(String, Result<CacheEntry, Error>)>>();
+    bar.finish();
+
+    // Only successful sources are remembered, so failures and removed files are retried or dropped.
+    cache.sources.clear();
+    let mut failures: Vec<(String, Error)> = Vec::new();
+    for (key, result) in results {
+        match result {
+            Ok(entry) => {
+                cache.sources.insert(key, entry);
+            }
+            Err(err) => failures.push((key, err)),
+        }
+    }
+    cache.save(cache_path)?;
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+    eprintln!("Could not build thumbnails for:");
+    for (path, err) in &failures {
+        eprintln!("  {}: {}", path, err);
+    }
+    Err(failure::err_msg(format!(
+        "{} of {} thumbnail jobs failed",
+        failures.len(),
+        images.len()
+    )))
+}
+
+/// Modern encodings written next to the derivatives in the source's own format, best first.
+/// AVIF is only available when built with the `avif` feature.
+pub fn alternate_formats() -> Vec<&'static str> {
+    let mut formats = Vec::new();
+    if cfg!(feature = "avif") {
+        formats.push("avif");
+    }
+    formats.push("webp");
+    formats
+}
+
+/// The path of a derivative of `source`, e.g. `IMG_1_small.webp` for `IMG_1.jpg`.
+fn derivative_path(source: &Path, kind: &str, ext: &str) -> Result<PathBuf, Error> {
+    let stem = source
+        .file_stem()
+        .and_then(|p| p.to_str())
+        .ok_or_else(|| failure::err_msg("File stem unwrap issue."))?;
+    Ok(source.with_file_name(format!("{}_{}.{}", stem, kind, ext)))
+}
+
+/// The thumbnails to write for a source with the given displayed dimensions.
+/// Sizes that would upscale the source are skipped, apart from the first, which is always written.
+pub fn thumbnail_sizes(settings: &Settings, width: u32, height: u32) -> Vec<Thumbnail> {
+    let panorama = width as f64 / height as f64 >= settings.panorama_ratio;
+    settings
+        .sizes
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, &size)| {
+            let box_width = if panorama {
+                (size as f64 * settings.panorama_stretch).round() as u32
+            } else {
+                size
+            };
+            let (fit_width, fit_height) = fit(width, height, box_width, size);
+            if idx == 0 || (fit_width <= width && fit_height <= height) {
+                Some(Thumbnail {
+                    size,
+                    width: fit_width,
+                    height: fit_height,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Scales `width` by `height` to fit in the box while keeping the aspect ratio,
+/// rounding the same way `DynamicImage::resize` does.
+fn fit(width: u32, height: u32, box_width: u32, box_height: u32) -> (u32, u32) {
+    let (width, height) = (u64::from(width), u64::from(height));
+    let (box_width, box_height) = (u64::from(box_width), u64::from(box_height));
+    let (fit_width, fit_height) = if box_width * height <= width * box_height {
+        (box_width, height * box_width / width)
+    } else {
+        (width * box_height / height, box_height)
+    };
+    (fit_width.max(1) as u32, fit_height.max(1) as u32)
+}
+
+/// The file suffix of a thumbnail: `small` for the first size, `small_{size}` for the others.
+fn thumbnail_kind(idx: usize, thumbnail: &Thumbnail) -> String {
+    if idx == 0 {
+        "small".to_string()
+    } else {
+        format!("small_{}", thumbnail.size)
+    }
+}
+
+/// The alternate formats the thumbnail of `source` exists in.
+pub fn available_formats(source: &Path) -> Vec<&'static str> {
+    alternate_formats()
+        .into_iter()
+        .filter(|ext| {
+            derivative_path(source, "small", ext)
+                .map(|path| path.exists())
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Whether a `_blur` image of `source` exists in its own format.
+pub fn has_blur(source: &Path) -> bool {
+    source
+        .extension()
+        .and_then(|p| p.to_str())
+        .and_then(|ext| derivative_path(source, "blur", ext).ok())
+        .map(|path| path.exists())
+        .unwrap_or(false)
+}
+
+/// Generate every thumbnail size and optionally a blur, in every format,
+/// unless the cache shows the current ones are up to date. Either way, returns the placeholder colours.
+fn derive(
+    path: &Path,
+    header: &ImageHeader,
+    cached: Option<&CacheEntry>,
+    settings: &Settings,
+) -> Result<CacheEntry, Error> {
+    let ext = path
+        .extension()
+        .and_then(|p| p.to_str())
+        .ok_or_else(|| failure::err_msg("Extension unwrap issue."))?;
+    let thumbnails = thumbnail_sizes(settings, header.width, header.height);
+    let formats = iter::once(ext)
+        .chain(alternate_formats())
+        .collect::<Vec<&str>>();
+    let mut outputs: Vec<PathBuf> = Vec::new();
+    for format in &formats {
+        for (idx, thumbnail) in thumbnails.iter().enumerate() {
+            outputs.push(derivative_path(
+                path,
+                &thumbnail_kind(idx, thumbnail),
+                format,
+            )?);
+        }
+        if settings.blur {
+            outputs.push(derivative_path(path, "blur", format)?);
+        }
+    }
+
+    let (modified, size) = cache::stat(path)?;
+    let derivatives_exist = outputs.iter().all(|output| output.exists());
+    let hash = match cached {
+        Some(entry) if entry.modified == modified && entry.size == size => entry.hash.clone(),
+        _ => cache::hash(path)?,
+    };
+    let mut entry = CacheEntry {
+        modified,
+        size,
+        hash,
+        settings: settings.clone(),
+        oriented: true,
+        placeholder: Vec::new(),
+    };
+    // Derivatives made before the cache existed, or before orientation was handled,
+    // are trusted unless the source needs rotating.
+    let up_to_date = match cached {
+        Some(cached) => {
+            cached.hash == entry.hash
+                && cached.settings == entry.settings
+                && (cached.oriented || is_upright(orientation(path)))
+        }
+        None => is_upright(orientation(path)),
+    };
+    if derivatives_exist && up_to_date {
+        entry.placeholder = match cached {
+            Some(cached) if !cached.placeholder.is_empty() => cached.placeholder.clone(),
+            // The existing thumbnail has the same colours and is much quicker to decode.
+            _ => placeholder(&image::open(derivative_path(path, "small", ext)?)?),
+        };
+        return Ok(entry);
+    }
+
+    let img = orient(image::open(path)?, orientation(path));
+    let resized = thumbnails
+        .iter()
+        .map(|thumbnail| img.resize_exact(thumbnail.width, thumbnail.height, Lanczos3))
+        .collect::<Vec<DynamicImage>>();
+    let small = resized
+        .first()
+        .ok_or_else(|| failure::err_msg("No thumbnail sizes are configured."))?;
+    entry.placeholder = placeholder(small);
+    let blurred = if settings.blur {
+        Some(small.blur(settings.blur_sigma))
+    } else {
+        None
+    };
+    for format in &formats {
+        for (idx, (thumbnail, thumb)) in thumbnails.iter().zip(&resized).enumerate() {
+            save(
+                thumb,
+                &derivative_path(path, &thumbnail_kind(idx, thumbnail), format)?,
+                settings,
+            )?;
+        }
+        if let Some(blurred) = &blurred {
+            save(blurred, &derivative_path(path, "blur", format)?, settings)?;
+        }
+    }
+    Ok(entry)
+}
+
+/// The average colour of each horizontal band of the image, top to bottom.
+/// The site paints these as a gradient until the thumbnail arrives.
+fn placeholder(img: &DynamicImage) -> Vec<String> {
+    let rgb = img.to_rgb();
+    let (width, height) = rgb.dimensions();
+    let bands = PLACEHOLDER_BANDS.min(height);
+    (0..bands)
+        .map(|band| {
+            let (top, bottom) = (band * height / bands, (band + 1) * height / bands);
+            let mut sum = [0u64; 3];
+            for y in top..bottom {
+                for x in 0..width {
+                    let pixel = rgb.get_pixel(x, y);
+                    for (total, channel) in sum.iter_mut().zip(pixel.data.iter()) {
+                        *total += u64::from(*channel);
+                    }
+                }
+            }
+            let count = u64::from(width) * u64::from(bottom - top);
+            format!(
+                "#{:02x}{:02x}{:02x}",
+                sum[0] / count,
+                sum[1] / count,
+                sum[2] / count
+            )
+        })
+        .collect()
+}
+
+/// The EXIF orientation of an image, treating unreadable metadata as upright.
+pub fn orientation(path: &Path) -> Orientation {
+    rexiv2::Metadata::new_from_path(path)
+        .map(|meta| meta.get_orientation())
+        .unwrap_or(Orientation::Unspecified)
+}
+
+fn is_upright(orientation: Orientation) -> bool {
+    matches!(orientation, Orientation::Unspecified | Orientation::Normal)
+}
+
+/// Whether displaying an image with this orientation swaps its width and height.
+pub fn swaps_axes(orientation: Orientation) -> bool {
+    matches!(
+        orientation,
+        Orientation::Rotate90HorizontalFlip
+            | Orientation::Rotate90
+            | Orientation::Rotate90VerticalFlip
+            | Orientation::Rotate270
+    )
+}
+
+/// Rotates and flips the decoded pixels so they appear as a viewer would show them.
+fn orient(img: DynamicImage, orientation: Orientation) -> DynamicImage {
+    match orientation {
+        Orientation::Unspecified | Orientation::Normal => img,
+        Orientation::HorizontalFlip => img.fliph(),
+        Orientation::Rotate180 => img.rotate180(),
+        Orientation::VerticalFlip => img.flipv(),
+        Orientation::Rotate90HorizontalFlip => img.rotate90().fliph(),
+        Orientation::Rotate90 => img.rotate90(),
+        Orientation::Rotate90VerticalFlip => img.rotate90().flipv(),
+        Orientation::Rotate270 => img.rotate270(),
+    }
+}
+
+/// Encodes to a hidden partial file first and renames it into place,
+/// so an interrupted run never leaves a truncated derivative behind.
+fn save(img: &DynamicImage, path: &Path, settings: &Settings) -> Result<(), Error> {
+    let name = path
+        .file_name()
+        .and_then(|p| p.to_str())
+        .ok_or_else(|| failure::err_msg("File name unwrap issue."))?;
+    let partial = path.with_file_name(format!(".{}.partial", name));
+
+    let written =
+        encode(img, path, &partial, settings).and_then(|_| Ok(fs::rename(&partial, path)?));
+    if written.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    written
+}
+
+/// Writes `img` to `partial`, in the format given by the extension of `path`.
+fn encode(
+    img: &DynamicImage,
+    path: &Path,
+    partial: &Path,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let ext = path
+        .extension()
+        .and_then(|p| p.to_str())
+        .map(|p| p.to_lowercase())
+        .unwrap_or_default();
+    let mut writer = BufWriter::new(File::create(partial)?);
+    match ext.as_str() {
+        "jpg" | "jpeg" => {
+            img.write_to(&mut writer, ImageOutputFormat::JPEG(settings.jpeg_quality))?
+        }
+        "png" => img.write_to(&mut writer, ImageOutputFormat::PNG)?,
+        "webp" => {
+            let rgba = img.to_rgba();
+            let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                .encode(settings.webp_quality);
+            writer.write_all(&encoded)?;
+        }
+        #[cfg(feature = "avif")]
+        "avif" => writer.write_all(&encode_avif(img, settings)?)?,
+        _ => return Err(failure::err_msg(format!("Cannot encode {} files", ext))),
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "avif")]
+fn encode_avif(img: &DynamicImage, settings: &Settings) -> Result<Vec<u8>, Error> {
+    let rgba = img.to_rgba();
+    let pixels = rgba
+        .pixels()
+        .map(|p| ravif::RGBA8::new(p[0], p[1], p[2], p[3]))
+        .collect::<Vec<ravif::RGBA8>>();
+    let encoded = ravif::Encoder::new()
+        .with_quality(settings.avif_quality)
+        .with_speed(settings.avif_speed)
+        // The thumbnail jobs already keep every core busy.
+        .with_num_threads(Some(1))
+        .encode_rgba(ravif::Img::new(
+            &pixels[..],
+            rgba.width() as usize,
+            rgba.height() as usize,
+        ))?;
+    Ok(encoded.avif_file)
+}
@@ -0,0 +1,644 @@
+use failure::Error;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+/// How many steps each axis is quantised into, the topojson CLI's default.
+const QUANTIZATION: f64 = 1e4;
+
+/// The properties the site reads; everything else in the GeoJSON is dropped.
+const PROPERTIES: &[&str] = &["name", "localname", "country"];
+
+/// The property whose value becomes a feature's id, so init.js can find countries by code.
+const ID_PROPERTY: &str = "su_a3";
+
+/// A position quantised to the topology's grid.
+type Point = [i64; 2];
+
+#[derive(Debug, Deserialize)]
+struct GeoCollection {
+    features: Vec<GeoFeature>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeoFeature {
+    #[serde(default)]
+    id: Option<Value>,
+    #[serde(default)]
+    properties: Option<Map<String, Value>>,
+    geometry: GeoGeometry,
+}
+
+/// Positions are read as vectors, as cities.json holds them in single precision.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "coordinates")]
+enum GeoGeometry {
+    Point(Vec<f64>),
+    MultiPoint(Vec<Vec<f64>>),
+    LineString(Vec<Vec<f64>>),
+    MultiLineString(Vec<Vec<Vec<f64>>>),
+    Polygon(Vec<Vec<Vec<f64>>>),
+    MultiPolygon(Vec<Vec<Vec<Vec<f64>>>>),
+}
+
+#[derive(Debug, Serialize)]
+struct Topology {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    transform: Transform,
+    objects: BTreeMap<String, Object>,
+    arcs: Vec<Vec<Point>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Transform {
+    scale: [f64; 2],
+    translate: [f64; 2],
+}
+
+#[derive(Debug, Serialize)]
+struct Object {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    geometries: Vec<TopoGeometry>,
+}
+
+#[derive(Debug, Serialize)]
+struct TopoGeometry {
+    #[serde(flatten)]
+    shape: TopoShape,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Value>,
+    #[serde(skip_serializing_if = "Map::is_empty")]
+    properties: Map<String, Value>,
+}
+
+/// Lines and rings refer to arcs by index, with `!index` for an arc walked backwards.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+enum TopoShape {
+    Point { coordinates: Point },
+    MultiPoint { coordinates: Vec<Point> },
+    LineString { arcs: Vec<i64> },
+    MultiLineString { arcs: Vec<Vec<i64>> },
+    Polygon { arcs: Vec<Vec<i64>> },
+    MultiPolygon { arcs: Vec<Vec<Vec<i64>>> },
+}
+
+/// Maps positions onto the integer grid spanning every input.
+struct Quantizer {
+    scale: [f64; 2],
+    translate: [f64; 2],
+}
+
+impl Quantizer {
+    fn new(collections: &[(String, GeoCollection)]) -> Result<Quantizer, Error> {
+        let mut min = [f64::INFINITY; 2];
+        let mut max = [f64::NEG_INFINITY; 2];
+        for (name, collection) in collections {
+            for feature in &collection.features {
+                feature
+                    .geometry
+                    .for_each_position(&mut |position| {
+                        let position = coordinates(position)?;
+                        for axis in 0..2 {
+                            min[axis] = min[axis].min(position[axis]);
+                            max[axis] = max[axis].max(position[axis]);
+                        }
+                        Ok(())
+                    })
+                    .map_err(|err| failure::err_msg(format!("{} in {}", err, name)))?;
+            }
+        }
+        if min[0] > max[0] {
+            min = [0.0; 2];
+            max = [0.0; 2];
+        }
+        let step = |axis: usize| {
+            let span = max[axis] - min[axis];
+            if span > 0.0 {
+                span / (QUANTIZATION - 1.0)
+            } else {
+                1.0
+            }
+        };
+        Ok(Quantizer {
+            scale: [step(0), step(1)],
+            translate: min,
+        })
+    }
+
+    fn point(&self, position: &[f64]) -> Result<Point, Error> {
+        let [x, y] = coordinates(position)?;
+        Ok([
+            ((x - self.translate[0]) / self.scale[0]).round() as i64,
+            ((y - self.translate[1]) / self.scale[1]).round() as i64,
+        ])
+    }
+
+    /// Quantises a line, dropping positions that land on the same point as the one before.
+    fn line(&self, positions: &[Vec<f64>]) -> Result<Vec<Point>, Error> {
+        let mut line: Vec<Point> = Vec::with_capacity(positions.len());
+        for position in positions {
+            let point = self.point(position)?;
+            if line.last() != Some(&point) {
+                line.push(point);
+            }
+        }
+        Ok(line)
+    }
+
+    /// Quantises a polygon, with the exterior ring clockwise and holes anticlockwise
+    /// as d3 expects of spherical polygons.
+    fn polygon(&self, rings: &[Vec<Vec<f64>>]) -> Result<Vec<Vec<Point>>, Error> {
+        rings
+            .iter()
+            .enumerate()
+            .map(|(index, ring)| {
+                let mut ring = self.line(ring)?;
+                let clockwise = signed_area(&ring) < 0;
+                if clockwise != (index == 0) {
+                    ring.reverse();
+                }
+                Ok(ring)
+            })
+            .collect()
+    }
+}
+
+impl GeoGeometry {
+    fn for_each_position<F: FnMut(&[f64]) -> Result<(), Error>>(
+        &self,
+        f: &mut F,
+    ) -> Result<(), Error> {
+        let lines: Vec<&Vec<Vec<f64>>> = match self {
+            GeoGeometry::Point(position) => return f(position),
+            GeoGeometry::MultiPoint(positions) | GeoGeometry::LineString(positions) => {
+                vec![positions]
+            }
+            GeoGeometry::MultiLineString(lines) | GeoGeometry::Polygon(lines) => {
+                lines.iter().collect()
+            }
+            GeoGeometry::MultiPolygon(polygons) => polygons.iter().flatten().collect(),
+        };
+        lines
+            .into_iter()
+            .flatten()
+            .try_for_each(|position| f(position))
+    }
+}
+
+/// The longitude and latitude of a GeoJSON position, which may also hold an altitude.
+fn coordinates(position: &[f64]) -> Result<[f64; 2], Error> {
+    match position {
+        [x, y, ..] if x.is_finite() && y.is_finite() => Ok([*x, *y]),
+        _ => Err(failure::err_msg(format!(
+            "{:?} is not a valid position",
+            position
+        ))),
+    }
+}
+
+/// Twice the area enclosed by a closed ring, positive if it runs anticlockwise.
+fn signed_area(ring: &[Point]) -> i64 {
+    ring.windows(2)
+        .map(|pair| pair[0][0] * pair[1][1] - pair[1][0] * pair[0][1])
+        .sum()
+}
+
+/// A geometry after quantisation, whose lines and rings are yet to be cut into arcs.
+enum Quantized {
+    Point(Point),
+    MultiPoint(Vec<Point>),
+    LineString(Vec<Point>),
+    MultiLineString(Vec<Vec<Point>>),
+    Polygon(Vec<Vec<Point>>),
+    MultiPolygon(Vec<Vec<Vec<Point>>>),
+}
+
+impl Quantized {
+    fn new(geometry: &GeoGeometry, quantizer: &Quantizer) -> Result<Quantized, Error> {
+        Ok(match geometry {
+            GeoGeometry::Point(position) => Quantized::Point(quantizer.point(position)?),
+            GeoGeometry::MultiPoint(positions) => Quantized::MultiPoint(
+                positions
+                    .iter()
+                    .map(|p| quantizer.point(p))
+                    .collect::<Result<_, Error>>()?,
+            ),
+            GeoGeometry::LineString(positions) => Quantized::LineString(quantizer.line(positions)?),
+            GeoGeometry::MultiLineString(lines) => Quantized::MultiLineString(
+                lines
+                    .iter()
+                    .map(|l| quantizer.line(l))
+                    .collect::<Result<_, Error>>()?,
+            ),
+            GeoGeometry::Polygon(rings) => Quantized::Polygon(quantizer.polygon(rings)?),
+            GeoGeometry::MultiPolygon(polygons) => Quantized::MultiPolygon(
+                polygons
+                    .iter()
+                    .map(|p| quantizer.polygon(p))
+                    .collect::<Result<_, Error>>()?,
+            ),
+        })
+    }
+
+    /// The geometry's open lines and closed rings.
+    fn lines(&self) -> (Vec<&Vec<Point>>, Vec<&Vec<Point>>) {
+        match self {
+            Quantized::Point(_) | Quantized::MultiPoint(_) => (vec![], vec![]),
+            Quantized::LineString(line) => (vec![line], vec![]),
+            Quantized::MultiLineString(lines) => (lines.iter().collect(), vec![]),
+            Quantized::Polygon(rings) => (vec![], rings.iter().collect()),
+            Quantized::MultiPolygon(polygons) => (vec![], polygons.iter().flatten().collect()),
+        }
+    }
+}
+
+/// The neighbours a point was first seen with, and whether it has since been seen with others.
+struct Neighbours {
+    first: (Point, Point),
+    junction: bool,
+}
+
+/// Finds the points where lines and rings meet or part ways. Arcs are cut there,
+/// so a border shared by two countries is stored once.
+fn junctions<'a, I: Iterator<Item = &'a Quantized>>(geometries: I) -> HashMap<Point, bool> {
+    let mut seen: HashMap<Point, Neighbours> = HashMap::new();
+    let mut visit = |point: Point, previous: Point, next: Point, end: bool| {
+        let pair = if previous <= next {
+            (previous, next)
+        } else {
+            (next, previous)
+        };
+        let neighbours = seen.entry(point).or_insert(Neighbours {
+            first: pair,
+            junction: false,
+        });
+        neighbours.junction |= end || neighbours.first != pair;
+    };
+
+    for geometry in geometries {
+        let (lines, rings) = geometry.lines();
+        for line in lines {
+            for (index, &point) in line.iter().enumerate() {
+                let previous = line[index.saturating_sub(1)];
+                let next = line[(index + 1).min(line.len() - 1)];
+                visit(point, previous, next, index == 0 || index == line.len() - 1);
+            }
+        }
+        for ring in rings {
+            // The last point repeats the first.
+            let count = ring.len().saturating_sub(1);
+            for index in 0..count {
+                let previous = ring[(index + count - 1) % count];
+                let next = ring[(index + 1) % count];
+                visit(ring[index], previous, next, false);
+            }
+        }
+    }
+    seen.into_iter()
+        .map(|(point, neighbours)| (point, neighbours.junction))
+        .collect()
+}
+
+/// Collects arcs, storing each once whichever way round it is walked.
+struct Arcs<'a> {
+    junctions: &'a HashMap<Point, bool>,
+    arcs: Vec<Vec<Point>>,
+    indices: HashMap<Vec<Point>, usize>,
+}
+
+impl<'a> Arcs<'a> {
+    fn is_junction(&self, point: &Point) -> bool {
+        self.junctions.get(point).cloned().unwrap_or(false)
+    }
+
+    fn index(&mut self, arc: Vec<Point>) -> i64 {
+        if let Some(&index) = self.indices.get(&arc) {
+            return index as i64;
+        }
+        let reversed: Vec<Point> = arc.iter().rev().cloned().collect();
+        if let Some(&index) = self.indices.get(&reversed) {
+            return !(index as i64);
+        }
+        let index = self.arcs.len();
+        self.indices.insert(arc.clone(), index);
+        self.arcs.push(arc);
+        index as i64
+    }
+
+    /// Cuts a line at its junctions; its ends always are.
+    fn line(&mut self, line: &[Point]) -> Vec<i64> {
+        if line.len() < 2 {
+            return vec![self.index(line.to_vec())];
+        }
+        let mut indices = Vec::new();
+        let mut start = 0;
+        for end in 1..line.len() {
+            if end == line.len() - 1 || self.is_junction(&line[end]) {
+                indices.push(self.index(line[start..=end].to_vec()));
+                start = end;
+            }
+        }
+        indices
+    }
+
+    /// Cuts a ring at its junctions. A ring without any is started at its smallest point,
+    /// so the same ring in another geometry becomes the same arc.
+    fn ring(&mut self, ring: &[Point]) -> Vec<i64> {
+        let count = ring.len().saturating_sub(1);
+        if count == 0 {
+            return vec![self.index(ring.to_vec())];
+        }
+        let start = (0..count)
+            .find(|&index| self.is_junction(&ring[index]))
+            .unwrap_or_else(|| (0..count).min_by_key(|&index| ring[index]).unwrap_or(0));
+        let rotated: Vec<Point> = (0..=count)
+            .map(|offset| ring[(start + offset) % count])
+            .collect();
+        self.line(&rotated)
+    }
+
+    /// Delta-encodes every arc after its first point, as the TopoJSON client expects.
+    fn encoded(self) -> Vec<Vec<Point>> {
+        self.arcs
+            .into_iter()
+            .map(|arc| {
+                let mut previous = [0, 0];
+                arc.into_iter()
+                    .map(|point| {
+                        let delta = [point[0] - previous[0], point[1] - previous[1]];
+                        previous = point;
+                        delta
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Keeps the properties the site reads, taking the id from `su_a3` where there is one.
+fn properties(feature: &GeoFeature) -> (Option<Value>, Map<String, Value>) {
+    let empty = Map::new();
+    let all = feature.properties.as_ref().unwrap_or(&empty);
+    let id = all.get(ID_PROPERTY).cloned().or_else(|| feature.id.clone());
+    let kept = all
+        .iter()
+        .filter(|(key, value)| PROPERTIES.contains(&key.as_str()) && !value.is_null())
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    (id, kept)
+}
+
+/// Builds a TopoJSON topology from GeoJSON feature collections, one object per file
+/// named after it, the way `topojson` from Node did.
+pub fn write_topology(inputs: &[&Path], output: &Path) -> Result<(), Error> {
+    let mut collections = Vec::new();
+    for path in inputs {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| failure::err_msg(format!("{} has no file name", path.display())))?;
+        let collection: GeoCollection = serde_json::from_reader(BufReader::new(File::open(path)?))
+            .map_err(|err| {
+                failure::err_msg(format!("Could not read {}: {}", path.display(), err))
+            })?;
+        collections.push((name.to_string(), collection));
+    }
+    let topology = topology(&collections)?;
+    serde_json::to_writer(BufWriter::new(File::create(output)?), &topology)?;
+    Ok(())
+}
+
+fn topology(collections: &[(String, GeoCollection)]) -> Result<Topology, Error> {
+    let quantizer = Quantizer::new(collections)?;
+    let quantized = collections
+        .iter()
+        .map(|(name, collection)| {
+            collection
+                .features
+                .iter()
+                .map(|feature| Quantized::new(&feature.geometry, &quantizer))
+                .collect::<Result<Vec<Quantized>, Error>>()
+                .map_err(|err| failure::err_msg(format!("{} in {}", err, name)))
+        })
+        .collect::<Result<Vec<Vec<Quantized>>, Error>>()?;
+    let junctions = junctions(quantized.iter().flatten());
+
+    let mut arcs = Arcs {
+        junctions: &junctions,
+        arcs: Vec::new(),
+        indices: HashMap::new(),
+    };
+    let mut objects = BTreeMap::new();
+    for ((name, collection), geometries) in collections.iter().zip(quantized) {
+        let geometries = collection
+            .features
+            .iter()
+            .zip(geometries)
+            .map(|(feature, geometry)| {
+                let shape = match geometry {
+                    Quantized::Point(coordinates) => TopoShape::Point { coordinates },
+                    Quantized::MultiPoint(coordinates) => TopoShape::MultiPoint { coordinates },
+                    Quantized::LineString(line) => TopoShape::LineString {
+                        arcs: arcs.line(&line),
+                    },
+                    Quantized::MultiLineString(lines) => TopoShape::MultiLineString {
+                        arcs: lines.iter().map(|line| arcs.line(line)).collect(),
+                    },
+                    Quantized::Polygon(rings) => TopoShape::Polygon {
+                        arcs: rings.iter().map(|ring| arcs.ring(ring)).collect(),
+                    },
+                    Quantized::MultiPolygon(polygons) => TopoShape::MultiPolygon {
+                        arcs: polygons
+                            .iter()
+                            .map(|rings| rings.iter().map(|ring| arcs.ring(ring)).collect())
+                            .collect(),
+                    },
+                };
+                let (id, properties) = properties(feature);
+                TopoGeometry {
+                    shape,
+                    id,
+                    properties,
+                }
+            })
+            .collect();
+        objects.insert(
+            name.clone(),
+            Object {
+                type_: "GeometryCollection",
+                geometries,
+            },
+        );
+    }
+
+    Ok(Topology {
+        type_: "Topology",
+        transform: Transform {
+            scale: quantizer.scale,
+            translate: quantizer.translate,
+        },
+        objects,
+        arcs: arcs.encoded(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn collection(geometries: Value) -> (String, GeoCollection) {
+        let features: Vec<Value> = geometries
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|geometry| json!({ "type": "Feature", "geometry": geometry }))
+            .collect();
+        (
+            "countries".to_string(),
+            serde_json::from_value(json!({ "features": features })).unwrap(),
+        )
+    }
+
+    fn square(x: f64, y: f64, size: f64) -> Value {
+        json!([
+            [x, y],
+            [x + size, y],
+            [x + size, y + size],
+            [x, y + size],
+            [x, y]
+        ])
+    }
+
+    fn rings(topology: &Topology) -> Vec<Vec<Vec<i64>>> {
+        topology.objects["countries"]
+            .geometries
+            .iter()
+            .map(|geometry| match &geometry.shape {
+                TopoShape::Polygon { arcs } => arcs.clone(),
+                shape => panic!("{:?} is not a polygon", shape),
+            })
+            .collect()
+    }
+
+    /// Undoes the delta encoding, giving each arc's points on the grid.
+    fn decoded(topology: &Topology) -> Vec<Vec<Point>> {
+        topology
+            .arcs
+            .iter()
+            .map(|arc| {
+                let mut position = [0, 0];
+                arc.iter()
+                    .map(|delta| {
+                        position = [position[0] + delta[0], position[1] + delta[1]];
+                        position
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Follows a ring's arcs, walking the negative ones backwards.
+    fn ring_points(arcs: &[Vec<Point>], indices: &[i64]) -> Vec<Point> {
+        let mut points: Vec<Point> = Vec::new();
+        for &index in indices {
+            let mut arc = if index < 0 {
+                arcs[!index as usize].iter().rev().cloned().collect()
+            } else {
+                arcs[index as usize].clone()
+            };
+            if !points.is_empty() {
+                arc.remove(0);
+            }
+            points.extend(arc);
+        }
+        points
+    }
+
+    #[test]
+    fn shared_borders_are_stored_once() {
+        let collections = vec![collection(json!([
+            { "type": "Polygon", "coordinates": [square(0.0, 0.0, 1.0)] },
+            { "type": "Polygon", "coordinates": [square(1.0, 0.0, 1.0)] },
+        ]))];
+        let topology = topology(&collections).unwrap();
+        let rings = rings(&topology);
+
+        assert_eq!(topology.arcs.len(), 3);
+        let shared: Vec<i64> = rings[1][0].iter().filter(|&&i| i < 0).cloned().collect();
+        assert_eq!(shared.len(), 1);
+        assert!(rings[0][0].contains(&!shared[0]));
+    }
+
+    #[test]
+    fn rings_without_junctions_are_one_closed_arc() {
+        let collections = vec![collection(json!([
+            { "type": "Polygon", "coordinates": [square(0.0, 0.0, 1.0)] },
+        ]))];
+        let topology = topology(&collections).unwrap();
+        let arcs = decoded(&topology);
+
+        assert_eq!(rings(&topology), vec![vec![vec![0]]]);
+        assert_eq!(arcs[0].len(), 5);
+        assert_eq!(arcs[0].first(), arcs[0].last());
+    }
+
+    #[test]
+    fn holes_wind_against_the_exterior() {
+        let collections = vec![collection(json!([
+            {
+                "type": "Polygon",
+                "coordinates": [square(0.0, 0.0, 4.0), square(1.0, 1.0, 2.0)]
+            },
+        ]))];
+        let topology = topology(&collections).unwrap();
+        let arcs = decoded(&topology);
+        let rings = rings(&topology);
+
+        assert_eq!(rings[0].len(), 2);
+        assert!(signed_area(&ring_points(&arcs, &rings[0][0])) < 0);
+        assert!(signed_area(&ring_points(&arcs, &rings[0][1])) > 0);
+    }
+
+    #[test]
+    fn arcs_decode_within_quantisation_error() {
+        let line = vec![
+            [-3.7038, 40.4168],
+            [2.1734, 41.3851],
+            [-0.3763, 39.4699],
+            [-5.9845, 37.3891],
+        ];
+        let collections = vec![collection(json!([
+            { "type": "LineString", "coordinates": line },
+        ]))];
+        let topology = topology(&collections).unwrap();
+        let arcs = decoded(&topology);
+        let Transform { scale, translate } = topology.transform;
+
+        assert_eq!(arcs.len(), 1);
+        assert_eq!(arcs[0].len(), line.len());
+        for (point, position) in arcs[0].iter().zip(&line) {
+            for axis in 0..2 {
+                let decoded = point[axis] as f64 * scale[axis] + translate[axis];
+                assert!((decoded - position[axis]).abs() <= scale[axis] / 2.0 + 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_positions_are_errors() {
+        let collections = vec![collection(json!([
+            { "type": "LineString", "coordinates": [[0.0, 0.0], [1.0]] },
+        ]))];
+        let err = topology(&collections).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[1.0] is not a valid position in countries"
+        );
+    }
+}
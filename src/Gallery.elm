@@ -1,12 +1,13 @@
-module Gallery exposing (Filter(..), blurURL, countryNames, displayURL, filterImages, imageURL, locale, locationCoordinates, locationNames, sortImages, thumbURL, tripId, tripNames)
+module Gallery exposing (Filter(..), blurSources, blurURL, countryNames, displayURL, filterImages, imageURL, locale, locationCoordinates, locationId, locationNames, placeholder, sortImages, thumbSources, thumbSrcset, thumbURL, tripId, tripNames)
 
 import List.Extra exposing (unconsLast)
-import Manifest exposing (Country(..), Date, Image, Location(..), Month(..), Trip(..), Year)
+import Manifest exposing (Country(..), Date, Direction(..), Image, LocalName, Location(..), Month(..), Trip(..), Year)
 import Ordering exposing (Ordering)
 
 
 type Alternate
     = Thumb
+    | Sized Int
     | Blur
 
 
@@ -40,6 +41,77 @@ blurURL image =
     String.join "/" [ imagePath image, alternateFile Blur image.file ]
 
 
+{-| A CSS background of the image's colours, to show until the thumbnail arrives.
+-}
+placeholder : Image -> String
+placeholder image =
+    case image.placeholder of
+        [] ->
+            "none"
+
+        [ colour ] ->
+            colour
+
+        colours ->
+            "linear-gradient(" ++ String.join ", " colours ++ ")"
+
+
+{-| Every size of the thumbnail, as a `srcset` value.
+-}
+thumbSrcset : Image -> String
+thumbSrcset image =
+    srcset image.file image
+
+
+{-| Mime types and `srcset` values of the thumbnail in each of the extra formats the manifester wrote, best first.
+-}
+thumbSources : Image -> List ( String, String )
+thumbSources image =
+    List.map
+        (\format -> ( "image/" ++ format, srcset (replaceExtension format image.file) image ))
+        image.formats
+
+
+blurSources : Image -> List ( String, String )
+blurSources image =
+    List.map
+        (\format ->
+            ( "image/" ++ format
+            , String.join "/" [ imagePath image, alternateFile Blur (replaceExtension format image.file) ]
+            )
+        )
+        image.formats
+
+
+srcset : String -> Image -> String
+srcset file image =
+    image.thumbnails
+        |> List.indexedMap
+            (\idx ( size, pixels ) ->
+                let
+                    alt =
+                        -- The first size is written without its size in the name
+                        if idx == 0 then
+                            Thumb
+
+                        else
+                            Sized size
+                in
+                String.join "/" [ imagePath image, alternateFile alt file ] ++ " " ++ String.fromInt pixels ++ "w"
+            )
+        |> String.join ", "
+
+
+replaceExtension : String -> String -> String
+replaceExtension ext file =
+    case unconsLast <| String.split "." file of
+        Just ( _, splitName ) ->
+            String.join "." (splitName ++ [ ext ])
+
+        Nothing ->
+            file
+
+
 displayURL : Image -> String
 displayURL image =
     let
@@ -69,6 +141,9 @@ alternateFile alt file =
                 Thumb ->
                     String.join "_small." [ name, ext ]
 
+                Sized size ->
+                    String.join ("_small_" ++ String.fromInt size ++ ".") [ name, ext ]
+
                 Blur ->
                     String.join "_blur." [ name, ext ]
 
@@ -84,7 +159,18 @@ countryNames =
 
 locationNames : List String
 locationNames =
-    List.map (\location -> Manifest.locationInformation location |> .name) Manifest.locationList
+    List.map (\location -> Manifest.locationInformation location |> .label) Manifest.locationList
+
+
+{-| The id of the location on the globe. Names alone aren't unique, so the country code comes first.
+-}
+locationId : Location -> String
+locationId location =
+    let
+        info =
+            Manifest.locationInformation location
+    in
+    Manifest.countryId info.country ++ "_" ++ String.replace " " "_" info.name
 
 
 locationCoordinates : Location -> ( Float, Float )
@@ -107,9 +193,9 @@ locale image =
         url =
             imageURL image
     in
-    case Manifest.countryLocalName info.country of
+    case Maybe.map isolate (Manifest.countryLocalName info.country) of
         Just localCountry ->
-            case Manifest.locationLocalName image.location of
+            case Maybe.map isolate (Manifest.locationLocalName image.location) of
                 Just localLocation ->
                     ( localLocation ++ ", " ++ localCountry ++ " (" ++ info.name ++ ", " ++ countryString ++ "); " ++ date ++ ".", info.name, url )
 
@@ -117,7 +203,7 @@ locale image =
                     ( info.name ++ ", " ++ localCountry ++ " (" ++ countryString ++ "); " ++ date ++ ".", info.name, url )
 
         Nothing ->
-            case Manifest.locationLocalName image.location of
+            case Maybe.map isolate (Manifest.locationLocalName image.location) of
                 Just localLocation ->
                     ( localLocation ++ " (" ++ info.name ++ "), " ++ countryString ++ "; " ++ date ++ ".", info.name, url )
 
@@ -125,6 +211,18 @@ locale image =
                     ( info.name ++ ", " ++ countryString ++ "; " ++ date ++ ".", info.name, url )
 
 
+{-| Keeps a local name in its own direction, so a right-to-left name doesn't reorder the English around it.
+-}
+isolate : LocalName -> String
+isolate local =
+    case local.direction of
+        LeftToRight ->
+            "\u{2066}" ++ local.name ++ "\u{2069}"
+
+        RightToLeft ->
+            "\u{2067}" ++ local.name ++ "\u{2069}"
+
+
 dateString : Date -> String
 dateString date =
     let
@@ -242,9 +340,22 @@ dateOrderLatest : Ordering Image
 dateOrderLatest =
     Ordering.byFieldWith yearOrdering (.date >> .year)
         |> Ordering.breakTiesWith (Ordering.byFieldWith monthOrdering (.date >> .month))
+        |> Ordering.breakTiesWith (Ordering.byField (.captured >> capturedKey))
         |> Ordering.breakTiesWith (Ordering.byField .file)
 
 
+{-| Undated images go last, as they do in the manifest.
+-}
+capturedKey : Maybe String -> ( Int, String )
+capturedKey captured =
+    case captured of
+        Just time ->
+            ( 0, time )
+
+        Nothing ->
+            ( 1, "" )
+
+
 yearOrdering : Ordering Year
 yearOrdering =
     Ordering.natural
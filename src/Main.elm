@@ -183,10 +183,22 @@ routeParser =
         , mapRoute (Parser.s "license") RouteLicense
         , mapRoute (Parser.s "trip" </> Parser.string) (\trip -> RouteTrip (sanitise trip |> Manifest.stringToTrip))
         , mapRoute Parser.string (\country -> RouteCountry (sanitise country |> Manifest.stringToCountry))
-        , mapRoute (Parser.string </> Parser.string) (\_ location -> RouteLocation (sanitise location |> Manifest.stringToLocation))
+        , mapRoute (Parser.string </> Parser.string) (\country location -> RouteLocation (locationRoute (sanitise country) (sanitise location)))
         ]
 
 
+{-| Locations are routed by country and name. Where the name is used in several countries, its label includes the country.
+-}
+locationRoute : String -> String -> Maybe Location
+locationRoute country location =
+    case Manifest.stringToLocation location of
+        Just found ->
+            Just found
+
+        Nothing ->
+            Manifest.stringToLocation (location ++ ", " ++ country)
+
+
 clearFocus : Url -> Url
 clearFocus url =
     case url.query of
@@ -236,7 +248,7 @@ routeModel route model =
                         info =
                             Manifest.locationInformation location
                     in
-                    ( { newModel | filter = ByLocation location, filterSelected = ( RadioLocation, info.name ) }, [ Ports.initMap ( 3, info.name |> String.replace " " "_", [ negate <| Tuple.first info.coordinates, negate <| Tuple.second info.coordinates ] ), clearQuery ] )
+                    ( { newModel | filter = ByLocation location, filterSelected = ( RadioLocation, info.label ) }, [ Ports.initMap ( 3, Gallery.locationId location, [ negate <| Tuple.first info.coordinates, negate <| Tuple.second info.coordinates ] ), clearQuery ] )
 
                 Nothing ->
                     resetRoute model
@@ -595,16 +607,16 @@ update msg model =
                 path =
                     case radio of
                         RadioLocation ->
-                            let
-                                country =
-                                    case Manifest.stringToLocation selection of
-                                        Just location ->
-                                            "/" ++ (Manifest.locationInformation location |> (\info -> Manifest.countryName info.country)) ++ "/"
+                            case Manifest.stringToLocation selection of
+                                Just location ->
+                                    let
+                                        info =
+                                            Manifest.locationInformation location
+                                    in
+                                    "/" ++ Manifest.countryName info.country ++ "/" ++ info.name |> String.replace " " "_"
 
-                                        Nothing ->
-                                            "/"
-                            in
-                            country ++ selection |> String.replace " " "_"
+                                Nothing ->
+                                    "/" ++ selection |> String.replace " " "_"
 
                         RadioTrip ->
                             "/trip/" ++ (String.replace " " "_" selection |> String.replace "/" "-")
@@ -770,10 +782,10 @@ update msg model =
                                                         ( { model | url = url }, Cmd.none )
 
                                                     else
-                                                        doUpdate (ByLocation newLocation) RadioLocation (Manifest.locationInformation newLocation |> .name) model
+                                                        doUpdate (ByLocation newLocation) RadioLocation (Manifest.locationInformation newLocation |> .label) model
 
                                                 _ ->
-                                                    doUpdate (ByLocation newLocation) RadioLocation (Manifest.locationInformation newLocation |> .name) model
+                                                    doUpdate (ByLocation newLocation) RadioLocation (Manifest.locationInformation newLocation |> .label) model
 
                                         Nothing ->
                                             ( { model | url = url }, Cmd.none )
@@ -1052,8 +1064,16 @@ displayImage image w h =
     in
     -- Note the - 8 here on the width is to take into account the two 4px margins in the css
     -- We also send in a float as the width attribute to clean up the right edge
-    Html.img
+    let
+        sizes =
+            Html.Attributes.attribute "sizes" (String.fromFloat (w - 8.0) ++ "px")
+    in
+    picture [ sizes ]
+        (Gallery.thumbSources image)
         [ src (Gallery.thumbURL image)
+        , Html.Attributes.attribute "srcset" (Gallery.thumbSrcset image)
+        , sizes
+        , Html.Attributes.style "background" (Gallery.placeholder image)
         , Html.Attributes.attribute "width" (String.fromFloat <| w - 8.0)
         , height h
         , Touch.onWithOptions "touchstart" swipeOptions (\_ -> TouchPreload image)
@@ -1061,7 +1081,33 @@ displayImage image w h =
         , onMouseEnter (PutLocale <| Gallery.locale image)
         , onMouseLeave PopLocale
         ]
+
+
+{-| An image that lets the browser pick the first of the alternate sources it can decode,
+falling back to the `src` of the img. Each source is a mime type and `srcset`,
+and shares `sourceAttributes` such as `sizes` with the img.
+-}
+picture : List (Html.Attribute Msg) -> List ( String, String ) -> List (Html.Attribute Msg) -> Html Msg
+picture sourceAttributes sources imgAttributes =
+    Html.node "picture"
         []
+        (List.map
+            (\( mime, set ) ->
+                Html.node "source" (Html.Attributes.type_ mime :: Html.Attributes.attribute "srcset" set :: sourceAttributes) []
+            )
+            sources
+            ++ [ Html.img imgAttributes [] ]
+        )
+
+
+credit : Manifest.Credit -> Html Msg
+credit info =
+    div [ Html.Attributes.class "credit" ]
+        [ Html.text "© "
+        , Html.a [ href info.authorUrl ] [ Html.text info.author ]
+        , Html.text " · "
+        , Html.a [ href info.license, Html.Attributes.attribute "rel" "license" ] [ Html.text "License" ]
+        ]
 
 
 zoomImage : Image -> Bool -> Bool -> Bool -> Bool -> Html Msg
@@ -1073,7 +1119,7 @@ zoomImage image showControls showPrevious showNext showDescription =
                     ( locale, _, _ ) =
                         Gallery.locale image
                 in
-                ( div [ Html.Attributes.class "description" ] [ Html.text locale, Html.br [] [], Html.text image.description ], Html.Attributes.class "" )
+                ( div [ Html.Attributes.class "description" ] [ Html.text locale, Html.br [] [], Html.text image.description, credit image.credit ], Html.Attributes.class "" )
 
             else
                 ( Html.text "", Html.Attributes.class "desc-off" )
@@ -1105,7 +1151,11 @@ zoomImage image showControls showPrevious showNext showDescription =
             }
     in
     div [ Html.Attributes.class "zoombox" ]
-        [ Html.img [ Html.Attributes.class "blur", src (Gallery.blurURL image) ] []
+        [ if image.blur then
+            picture [] (Gallery.blurSources image) [ Html.Attributes.class "blur", src (Gallery.blurURL image) ]
+
+          else
+            div [ Html.Attributes.class "blur", Html.Attributes.style "background" (Gallery.placeholder image) ] []
         , Html.img
             [ src (Gallery.imageURL image)
             , Html.Attributes.class "zoom"
@@ -1504,7 +1554,7 @@ updateMap radio selected clearPrevious =
                         coordinates =
                             Gallery.locationCoordinates location
                     in
-                    port_ ( String.replace " " "_" selected, [ negate <| Tuple.first coordinates, negate <| Tuple.second coordinates ] )
+                    port_ ( Gallery.locationId location, [ negate <| Tuple.first coordinates, negate <| Tuple.second coordinates ] )
 
                 Nothing ->
                     port_ ( "", [] )
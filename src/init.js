@@ -576,7 +576,7 @@ function drawMap(callback) {
             .enter().append("path").attr("class", "iglobe-countries").attr("id", function(d, i) { return d.id; });
         svg.insert("g", ".iglobe-foreground").attr("id", "cities");
         d3.selectAll("#cities").selectAll("path").data(cities).enter().append("path").attr("class", "iglobe-cities")
-            .attr("id", function(d, i) { return d.properties.name.replace(/ /g,"_"); }).selectAll("LineString").attr("class", "iglobe-route");
+            .attr("id", function(d, i) { return d.properties.country + "_" + d.properties.name.replace(/ /g,"_"); }).selectAll("LineString").attr("class", "iglobe-route");
         svg.insert("g", "#cities").attr("id", "routes");
         d3.selectAll("#routes").selectAll("path").data(topojson.feature(n, n.objects.trips).features).enter()
             .append("path").attr("id", function(d) { return d.properties.name; }).attr("class", "iglobe-route")